@@ -0,0 +1,247 @@
+use crate::error::{Result, Error};
+use crate::bits::{BitOrder, BitReader, BitWriter};
+use std::io::{Read, Write};
+
+/// 256 byte values plus the not yet transmitted (NYT) node, each with a parent
+const MAX_NODES: usize = 2 * 257 - 1;
+const ROOT: usize = MAX_NODES - 1;
+/// a new symbol follows the NYT code as 9 raw bits, this value ends the stream
+const END_OF_STREAM: u64 = 256;
+const RAW_BITS: u32 = 9;
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Default)]
+struct AdaptiveNode {
+    weight: u64,
+    parent: usize,
+    left: Option<usize>,
+    right: Option<usize>,
+    sym: Option<u8>,
+}
+
+/// The FGK adaptive huffman tree. Encoder and decoder both start from a tree that only holds
+/// the NYT node and update it after every symbol the same way, so no table is transmitted.
+/// Nodes are stored by their number in the sibling property order, the root has the highest.
+struct Tree {
+    nodes: Vec<AdaptiveNode>,
+    leaf_of: [Option<usize>; 256],
+    nyt: usize,
+}
+
+impl Tree {
+    fn new() -> Self {
+        Self {
+            nodes: vec![AdaptiveNode::default(); MAX_NODES],
+            leaf_of: [None; 256],
+            nyt: ROOT,
+        }
+    }
+
+    fn is_leaf(&self, idx: usize) -> bool {
+        self.nodes[idx].left.is_none()
+    }
+
+    /// the code of node `idx`, collected from the leaf up, so the first bit is last
+    fn path(&self, mut idx: usize, path: &mut Vec<u8>) {
+        path.clear();
+        while idx != ROOT {
+            let parent = self.nodes[idx].parent;
+            path.push((self.nodes[parent].right == Some(idx)) as u8);
+            idx = parent;
+        }
+    }
+
+    /// counts one more occurrence of `sym`, adding it to the tree if it is new
+    fn update(&mut self, sym: u8) {
+        let mut idx = match self.leaf_of[sym as usize] {
+            Some(idx) => idx,
+            None => {
+                //the NYT node gets the new NYT as left and the new leaf as right child
+                let old = self.nyt;
+                let (nyt, leaf) = (old - 2, old - 1);
+                self.nodes[old].left = Some(nyt);
+                self.nodes[old].right = Some(leaf);
+                self.nodes[leaf] = AdaptiveNode { weight: 0, parent: old, left: None, right: None, sym: Some(sym) };
+                self.nodes[nyt] = AdaptiveNode { weight: 0, parent: old, left: None, right: None, sym: None };
+                self.nyt = nyt;
+                self.leaf_of[sym as usize] = Some(leaf);
+                leaf
+            },
+        };
+        loop {
+            //move the node to the highest number of its weight class before incrementing
+            let weight = self.nodes[idx].weight;
+            let mut leader = idx;
+            while leader < ROOT && self.nodes[leader + 1].weight == weight {
+                leader += 1;
+            }
+            if leader != idx && leader != self.nodes[idx].parent {
+                self.swap(idx, leader);
+                idx = leader;
+            }
+            self.nodes[idx].weight += 1;
+            if idx == ROOT {
+                break;
+            }
+            idx = self.nodes[idx].parent;
+        }
+    }
+
+    /// exchanges the subtrees at the positions `a` and `b`, both of the same weight
+    fn swap(&mut self, a: usize, b: usize) {
+        let (node_a, node_b) = (self.nodes[a], self.nodes[b]);
+        self.nodes[a] = AdaptiveNode { parent: node_a.parent, ..node_b };
+        self.nodes[b] = AdaptiveNode { parent: node_b.parent, ..node_a };
+        for idx in [a, b] {
+            let node = self.nodes[idx];
+            for child in [node.left, node.right].iter().flatten() {
+                self.nodes[*child].parent = idx;
+            }
+            if let Some(sym) = node.sym {
+                self.leaf_of[sym as usize] = Some(idx);
+            }
+            if node.sym.is_none() && node.left.is_none() {
+                self.nyt = idx;
+            }
+        }
+    }
+}
+
+fn write_path<W: Write>(writer: &mut BitWriter<W>, path: &[u8]) -> Result<()> {
+    for bit in path.iter().rev() {
+        writer.write_bits(*bit as u64, 1)?;
+    }
+    Ok(())
+}
+
+/// Compresses `input` in one pass, every byte is coded as soon as it is read.
+/// Bytes seen for the first time are sent as the NYT code plus 9 raw bits, the raw value 256
+/// ends the stream. Returns the number of bytes read.
+pub fn compress<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<u64> {
+    let mut tree = Tree::new();
+    let mut writer = BitWriter::new(output, BitOrder::Lsb);
+    let mut chunk = vec![0u8; CHUNK];
+    let mut path = Vec::new();
+    let mut total = 0u64;
+    loop {
+        let got = match input.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(got) => got,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(Error::Compress("could not read input".to_string(), err.to_string())),
+        };
+        for sym in chunk[..got].iter() {
+            match tree.leaf_of[*sym as usize] {
+                Some(leaf) => {
+                    tree.path(leaf, &mut path);
+                    write_path(&mut writer, &path)?;
+                },
+                None => {
+                    tree.path(tree.nyt, &mut path);
+                    write_path(&mut writer, &path)?;
+                    writer.write_bits(*sym as u64, RAW_BITS)?;
+                },
+            }
+            tree.update(*sym);
+        }
+        total += got as u64;
+    }
+    tree.path(tree.nyt, &mut path);
+    write_path(&mut writer, &path)?;
+    writer.write_bits(END_OF_STREAM, RAW_BITS)?;
+    writer.finish()?;
+    Ok(total)
+}
+
+/// Decompresses a stream written by adaptive `compress`, returns the number of bytes written.
+pub fn decompress<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<u64> {
+    let mut tree = Tree::new();
+    let mut reader = BitReader::new(input, BitOrder::Lsb);
+    let mut out = Vec::with_capacity(CHUNK);
+    let mut total = 0u64;
+    loop {
+        let mut idx = ROOT;
+        while !tree.is_leaf(idx) {
+            let node = tree.nodes[idx];
+            idx = match reader.read_bit()? {
+                0 => node.left,
+                _ => node.right,
+            }.unwrap_or(idx);
+        }
+        let sym = match tree.nodes[idx].sym {
+            Some(sym) => sym,
+            None => match reader.read_bits(RAW_BITS)? {
+                END_OF_STREAM => break,
+                raw if raw < 256 && tree.leaf_of[raw as usize].is_none() => raw as u8,
+                raw => return Err(Error::DeCompress("invalid new symbol in adaptive stream".to_string(), format!("{raw}"))),
+            },
+        };
+        tree.update(sym);
+        out.push(sym);
+        if out.len() == CHUNK {
+            output.write_all(&out).map_err(|err| Error::Finalizing("could not write output".to_string(), err.to_string()))?;
+            total += out.len() as u64;
+            out.clear();
+        }
+    }
+    output.write_all(&out).map_err(|err| Error::Finalizing("could not write output".to_string(), err.to_string()))?;
+    output.flush().map_err(|err| Error::Finalizing("could not flush output".to_string(), err.to_string()))?;
+    Ok(total + out.len() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> (Vec<u8>, usize) {
+        let mut compressed = Vec::new();
+        assert_eq!(compress(&mut &data[..], &mut compressed).unwrap(), data.len() as u64);
+        let mut decompressed = Vec::new();
+        assert_eq!(decompress(&mut compressed.as_slice(), &mut decompressed).unwrap(), data.len() as u64);
+        (decompressed, compressed.len())
+    }
+
+    #[test]
+    fn sibling_property() {
+        let mut tree = Tree::new();
+        for sym in b"abracadabra, mississippi".iter() {
+            tree.update(*sym);
+            //weights never decrease with the node number and parents sum up their children
+            for idx in tree.nyt..ROOT {
+                assert!(tree.nodes[idx].weight <= tree.nodes[idx + 1].weight);
+            }
+            for idx in tree.nyt..=ROOT {
+                let node = tree.nodes[idx];
+                if let (Some(left), Some(right)) = (node.left, node.right) {
+                    assert_eq!(node.weight, tree.nodes[left].weight + tree.nodes[right].weight);
+                }
+            }
+        }
+        assert_eq!(tree.nodes[ROOT].weight, 24);
+    }
+
+    #[test]
+    fn text() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let data = &text[..100 * 1024];
+        let (decompressed, size) = round_trip(data);
+        assert_eq!(decompressed, data);
+        assert!(size < data.len() * 6 / 10);
+    }
+
+    #[test]
+    fn all_bytes_and_empty() {
+        let data: Vec<u8> = (0..=255u8).chain((0..=255u8).rev()).chain(std::iter::repeat(7).take(1000)).collect();
+        assert_eq!(round_trip(&data).0, data);
+        assert_eq!(round_trip(&[]).0, Vec::<u8>::new());
+        assert_eq!(round_trip(&[42]).0, vec![42]);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut compressed = Vec::new();
+        compress(&mut &b"some adaptive data"[..], &mut compressed).unwrap();
+        let mut out = Vec::new();
+        assert!(decompress(&mut &compressed[..compressed.len() - 2], &mut out).is_err());
+    }
+}
@@ -0,0 +1,748 @@
+use crate::error::{Result, Error};
+use crate::format::{self, Header, Meta, FLAG_ADAPTIVE, FLAG_INDEX};
+use crate::crc::{crc32, CrcWriter};
+use crate::huffman::{Decoder, Mode};
+use crate::stream::{Options, MAX_BLOCK_SIZE};
+use crate::utils::{file_meta, restore_meta};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// first bytes of every archive
+pub const ARCHIVE_MAGIC: [u8; 4] = [0x89, b'H', b'U', b'A'];
+pub const ARCHIVE_VERSION: u8 = 1;
+/// last bytes of every archive, after the index position and checksum
+const TRAILER_MAGIC: [u8; 4] = [b'A', b'U', b'H', 0x89];
+/// magic, version and flags
+const PREFIX_LEN: u64 = 6;
+/// index offset, index checksum and magic
+const TRAILER_LEN: u64 = 16;
+/// archive flag: small files share one compressed stream, their entries record where they start
+pub const ARCHIVE_SOLID: u8 = 0x01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    File,
+    Dir,
+}
+
+/// a member of an archive as recorded in the index
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// relative path with '/' as separator
+    pub path: String,
+    pub kind: Kind,
+    /// position of the compressed member in the archive
+    pub offset: u64,
+    /// length of the compressed member
+    pub length: u64,
+    /// length of the original file
+    pub size: u64,
+    /// where the file starts in the decompressed member, only solid groups hold more than one file
+    pub start: u64,
+    pub meta: Meta,
+}
+
+/// true if `prefix` starts with the archive magic bytes
+pub fn is_archive(prefix: &[u8]) -> bool {
+    prefix.starts_with(&ARCHIVE_MAGIC)
+}
+
+/// Creates the archive `archive` from `inputs`, directories are added with everything below them.
+/// An archive is laid out as 4 bytes magic, 1 byte version, 1 byte flags, the members,
+/// the index and a trailer of the index offset (8 bytes), the CRC-32 of the index (4 bytes)
+/// and 4 bytes magic. Every member is a complete compressed stream (see `format::compress`)
+/// with the header `flags` set, so members can be extracted one by one.
+/// A `solid` archive puts files of the same extension into shared members of up to
+/// `options.block_size` bytes, which are coded with one table.
+/// Symbolic links are skipped. Returns the index.
+pub fn create(archive: &Path, inputs: &[PathBuf], options: &Options, flags: u8, solid: bool) -> Result<Vec<Entry>> {
+    let sources = gather(inputs)?;
+    let file = OpenOptions::new().create(true).write(true).truncate(true).open(archive)
+        .map_err(|err| Error::Compress(format!("could not create archive '{}'", archive.display()), err.to_string()))?;
+    let sources = without_archive(sources, archive);
+    let mut output = BufWriter::new(file);
+    let archive_flags = if solid { ARCHIVE_SOLID } else { 0 };
+    let result = write_prefix(&mut output, archive_flags)
+        .and_then(|_| write_members(&mut output, &sources, options, flags, solid, PREFIX_LEN))
+        .and_then(|(entries, end)| finish(&mut output, entries, end, archive_flags));
+    if result.is_err() {
+        let _ = fs::remove_file(archive);
+    }
+    result
+}
+
+/// Adds `inputs` to the end of the archive `archive`, creating it (`solid` or not) if it does
+/// not exist. Members with the same path as an added one are dropped from the index, their bytes
+/// stay until the next `delete` compacts the archive. Returns the new index.
+pub fn add(archive: &Path, inputs: &[PathBuf], options: &Options, flags: u8, solid: bool) -> Result<Vec<Entry>> {
+    if !archive.exists() {
+        return create(archive, inputs, options, flags, solid);
+    }
+    let sources = without_archive(gather(inputs)?, archive);
+    let mut file = OpenOptions::new().read(true).write(true).open(archive)
+        .map_err(|err| Error::Compress(format!("could not open archive '{}'", archive.display()), err.to_string()))?;
+    let (old, offset, archive_flags) = read_archive(&mut file)?;
+    //the new members overwrite the old index, it is written back if anything fails
+    let result = file.seek(SeekFrom::Start(offset))
+        .map_err(|err| Error::Compress("could not write archive".to_string(), err.to_string()))
+        .and_then(|_| {
+            let mut output = BufWriter::new(&mut file);
+            let (added, end) = write_members(&mut output, &sources, options, flags, archive_flags & ARCHIVE_SOLID != 0, offset)?;
+            let mut entries: Vec<Entry> = old.iter().filter(|entry| !added.iter().any(|new| new.path == entry.path)).cloned().collect();
+            entries.extend(added);
+            finish(&mut output, entries, end, archive_flags)
+        });
+    let result = result.and_then(|entries| {
+        let len = file.stream_position().map_err(|err| Error::Compress("could not write archive".to_string(), err.to_string()))?;
+        file.set_len(len).map_err(|err| Error::Compress("could not write archive".to_string(), err.to_string()))?;
+        Ok(entries)
+    });
+    if result.is_err() {
+        let _ = file.set_len(offset)
+            .and_then(|_| file.seek(SeekFrom::Start(offset)))
+            .map_err(|err| Error::Compress("could not restore archive".to_string(), err.to_string()))
+            .and_then(|_| finish(&mut file, old, offset, archive_flags));
+    }
+    result
+}
+
+/// Removes the members named by `selected` and compacts the archive, the remaining members are
+/// copied into a new archive that replaces the old one. Solid groups that lost a file are
+/// compressed again with `options`, only symbol mode and entropy coder are kept from their
+/// header, which records nothing else. Returns the removed entries.
+pub fn delete(archive: &Path, selected: &[String], options: &Options, decoder: Decoder) -> Result<Vec<Entry>> {
+    if selected.is_empty() {
+        return Err(Error::Args("name the members to delete".to_string(), String::new()));
+    }
+    let mut input = BufReader::new(File::open(archive).map_err(|err| Error::Compress(format!("could not open archive '{}'", archive.display()), err.to_string()))?);
+    let (entries, _, archive_flags) = read_archive(&mut input)?;
+    let removed: Vec<Entry> = select(&entries, selected)?.into_iter().cloned().collect();
+    let kept: Vec<Entry> = entries.iter().filter(|entry| !removed.contains(entry)).cloned().collect();
+
+    let mut compacted = archive.as_os_str().to_os_string();
+    compacted.push(".tmp");
+    let compacted = PathBuf::from(compacted);
+    let file = File::create(&compacted).map_err(|err| Error::Compress("could not create compacted archive".to_string(), err.to_string()))?;
+    let mut output = BufWriter::new(file);
+    let result = write_prefix(&mut output, archive_flags)
+        .and_then(|_| copy_members(&mut input, &mut output, &entries, kept, options, decoder))
+        .and_then(|(entries, end)| finish(&mut output, entries, end, archive_flags))
+        .and_then(|_| output.into_inner().map_err(|err| Error::Compress("could not write compacted archive".to_string(), err.to_string())))
+        .and_then(|_| fs::rename(&compacted, archive).map_err(|err| Error::Compress("could not replace archive".to_string(), err.to_string())));
+    if result.is_err() {
+        let _ = fs::remove_file(&compacted);
+    }
+    result.map(|_| removed)
+}
+
+/// the index of the archive at `archive`
+pub fn list(archive: &Path) -> Result<Vec<Entry>> {
+    let file = File::open(archive).map_err(|err| Error::DeCompress(format!("could not open archive '{}'", archive.display()), err.to_string()))?;
+    read_index(&mut BufReader::new(file))
+}
+
+fn write_prefix<W: Write>(output: &mut W, archive_flags: u8) -> Result<()> {
+    let mut prefix = ARCHIVE_MAGIC.to_vec();
+    prefix.extend_from_slice(&[ARCHIVE_VERSION, archive_flags]);
+    output.write_all(&prefix).map_err(|err| Error::Compress("could not write archive".to_string(), err.to_string()))
+}
+
+/// Writes the members of the `kept` entries of `input` one after another. Members that keep
+/// all their files are copied as they are, the rest of a solid group is compressed again with `options`.
+/// `entries` is the whole old index. Returns the entries at their new places and the offset after them.
+fn copy_members<R: Read + Seek, W: Write>(input: &mut R, output: &mut W, entries: &[Entry], mut kept: Vec<Entry>, options: &Options, decoder: Decoder) -> Result<(Vec<Entry>, u64)> {
+    let mut offset = PREFIX_LEN;
+    //the offsets are overwritten on the way, groups are found by the old ones
+    let old_offsets: Vec<u64> = kept.iter().map(|entry| entry.offset).collect();
+    let mut done: Vec<u64> = Vec::new();
+    for idx in 0..kept.len() {
+        if kept[idx].kind == Kind::Dir {
+            kept[idx].offset = offset;
+            continue;
+        }
+        let old = old_offsets[idx];
+        if done.contains(&old) {
+            continue;
+        }
+        done.push(old);
+        let group: Vec<usize> = (idx..kept.len()).filter(|other| kept[*other].kind == Kind::File && old_offsets[*other] == old).collect();
+        let complete = entries.iter().filter(|entry| entry.kind == Kind::File && entry.offset == old).count() == group.len();
+        if complete {
+            input.seek(SeekFrom::Start(old)).map_err(|err| Error::DeCompress("could not read archive member".to_string(), err.to_string()))?;
+            let copied = std::io::copy(&mut input.take(kept[idx].length), output).map_err(|err| Error::Compress("could not copy archive member".to_string(), err.to_string()))?;
+            if copied != kept[idx].length {
+                return Err(Error::DeCompress("archive member is truncated".to_string(), kept[idx].path.clone()));
+            }
+            for member in group {
+                kept[member].offset = offset;
+            }
+            offset += copied;
+            continue;
+        }
+        let (header, decompressed) = read_member(input, &kept[idx], decoder)?;
+        let mut data = Vec::new();
+        for member in group.iter() {
+            let entry = &mut kept[*member];
+            let range = entry.start as usize..(entry.start + entry.size) as usize;
+            let bytes = decompressed.get(range).ok_or_else(|| Error::DeCompress("solid member is shorter than its files".to_string(), entry.path.clone()))?;
+            entry.start = data.len() as u64;
+            data.extend_from_slice(bytes);
+        }
+        let options = Options { text: header.mode == Mode::Text, block_size: data.len().clamp(1, MAX_BLOCK_SIZE), coder: header.coder, ..options.clone() };
+        let mut counted = CrcWriter::new(&mut *output);
+        compress_member(&mut data.as_slice(), &mut counted, &options, header.flags & (FLAG_ADAPTIVE | FLAG_INDEX))?;
+        for member in group {
+            kept[member].offset = offset;
+            kept[member].length = counted.count;
+        }
+        offset += counted.count;
+    }
+    Ok((kept, offset))
+}
+
+/// every file and directory of `inputs` with its path in the archive
+fn gather(inputs: &[PathBuf]) -> Result<Vec<(PathBuf, String, Kind)>> {
+    let mut sources = Vec::new();
+    for input in inputs {
+        let name = root_name(input)?;
+        collect(input, name, &mut sources)?;
+    }
+    Ok(sources)
+}
+
+/// drops the archive itself from `sources`, it must not end up inside itself
+fn without_archive(mut sources: Vec<(PathBuf, String, Kind)>, archive: &Path) -> Vec<(PathBuf, String, Kind)> {
+    let own = fs::canonicalize(archive).ok();
+    sources.retain(|(source, _, _)| fs::canonicalize(source).ok() != own);
+    sources
+}
+
+/// compresses `sources` as members starting at `offset`, returns their entries and the offset after them
+fn write_members<W: Write>(output: &mut W, sources: &[(PathBuf, String, Kind)], options: &Options, flags: u8, solid: bool, mut offset: u64) -> Result<(Vec<Entry>, u64)> {
+    let mut entries: Vec<Entry> = sources.iter().map(|(source, path, kind)| Entry {
+        path: path.clone(),
+        kind: *kind,
+        offset,
+        length: 0,
+        size: 0,
+        start: 0,
+        meta: file_meta(source).unwrap_or(Meta { permissions: 0o644, mtime: 0 }),
+    }).collect();
+    let mut files: Vec<usize> = (0..sources.len()).filter(|idx| sources[*idx].2 == Kind::File).collect();
+    if solid {
+        //similar files go next to each other
+        files.sort_by_key(|idx| Path::new(sources[*idx].1.as_str()).extension().map(|ext| ext.to_os_string()));
+    }
+    let mut group: Vec<usize> = Vec::new();
+    let mut data = Vec::new();
+    for idx in files {
+        let source = sources[idx].0.as_path();
+        let len = fs::metadata(source).map(|meta| meta.len()).unwrap_or(0);
+        if !solid || len >= options.block_size as u64 {
+            //a member of its own is streamed from the file
+            let file = File::open(source).map_err(|err| Error::Compress(format!("could not open file '{}'", source.display()), err.to_string()))?;
+            let mut counted = CrcWriter::new(&mut *output);
+            entries[idx].size = compress_member(&mut BufReader::new(file), &mut counted, options, flags)?;
+            entries[idx].offset = offset;
+            entries[idx].length = counted.count;
+            offset += counted.count;
+            continue;
+        }
+        if data.len() as u64 + len > options.block_size as u64 {
+            offset = write_group(output, &data, &group, &mut entries, options, flags, offset)?;
+            group.clear();
+            data.clear();
+        }
+        let start = data.len();
+        File::open(source)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|err| Error::Compress(format!("could not read file '{}'", source.display()), err.to_string()))?;
+        entries[idx].start = start as u64;
+        entries[idx].size = (data.len() - start) as u64;
+        group.push(idx);
+    }
+    if !group.is_empty() {
+        offset = write_group(output, &data, &group, &mut entries, options, flags, offset)?;
+    }
+    Ok((entries, offset))
+}
+
+/// compresses the concatenated files of a solid `group` as one member, returns the offset after it
+fn write_group<W: Write>(output: &mut W, data: &[u8], group: &[usize], entries: &mut [Entry], options: &Options, flags: u8, offset: u64) -> Result<u64> {
+    let mut counted = CrcWriter::new(&mut *output);
+    compress_member(&mut &data[..], &mut counted, options, flags)?;
+    for idx in group {
+        entries[*idx].offset = offset;
+        entries[*idx].length = counted.count;
+    }
+    Ok(offset + counted.count)
+}
+
+/// writes the index for the members that end at `offset` and flushes
+fn finish<W: Write>(output: &mut W, entries: Vec<Entry>, offset: u64, archive_flags: u8) -> Result<Vec<Entry>> {
+    write_index(output, &entries, offset, archive_flags)?;
+    output.flush().map_err(|err| Error::Compress("could not write archive".to_string(), err.to_string()))?;
+    Ok(entries)
+}
+
+/// compresses one member, returns the original size
+fn compress_member<R: Read, W: Write>(input: &mut R, output: &mut W, options: &Options, flags: u8) -> Result<u64> {
+    let mode = if options.text && flags & FLAG_ADAPTIVE == 0 { Mode::Text } else { Mode::Bytes };
+    let mut header = Header::new(mode, None);
+    header.flags |= flags;
+    format::compress(input, output, &header, options)
+}
+
+/// the name `input` gets in the archive, its last component
+fn root_name(input: &Path) -> Result<String> {
+    let absolute = fs::canonicalize(input).map_err(|err| Error::Compress(format!("could not find '{}'", input.display()), err.to_string()))?;
+    absolute.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_string())
+        .ok_or_else(|| Error::Compress("input has no usable name".to_string(), input.display().to_string()))
+}
+
+/// appends `source` and, for a directory, everything below it in name order
+pub fn collect(source: &Path, path: String, sources: &mut Vec<(PathBuf, String, Kind)>) -> Result<()> {
+    let meta = fs::symlink_metadata(source).map_err(|err| Error::Compress(format!("could not read '{}'", source.display()), err.to_string()))?;
+    if meta.is_file() {
+        sources.push((source.to_path_buf(), path, Kind::File));
+    } else if meta.is_dir() {
+        sources.push((source.to_path_buf(), path.clone(), Kind::Dir));
+        let mut children = fs::read_dir(source)
+            .and_then(|dir| dir.map(|child| child.map(|child| child.path())).collect::<std::io::Result<Vec<PathBuf>>>())
+            .map_err(|err| Error::Compress(format!("could not read directory '{}'", source.display()), err.to_string()))?;
+        children.sort();
+        for child in children {
+            let name = match child.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_string(),
+                None => return Err(Error::Compress("file names have to be valid utf-8".to_string(), child.display().to_string())),
+            };
+            collect(&child, format!("{}/{}", path, name), sources)?;
+        }
+    }
+    Ok(())
+}
+
+/// writes the index, the start of every file is only stored in solid archives
+fn write_index<W: Write>(output: &mut W, entries: &[Entry], offset: u64, archive_flags: u8) -> Result<()> {
+    let mut index = Vec::new();
+    index.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for entry in entries {
+        if entry.path.len() > u16::MAX as usize {
+            return Err(Error::Compress("path is too long for the archive".to_string(), entry.path.clone()));
+        }
+        index.push(match entry.kind { Kind::File => 0, Kind::Dir => 1 });
+        index.extend_from_slice(&(entry.path.len() as u16).to_be_bytes());
+        index.extend_from_slice(entry.path.as_bytes());
+        index.extend_from_slice(&entry.offset.to_be_bytes());
+        index.extend_from_slice(&entry.length.to_be_bytes());
+        index.extend_from_slice(&entry.size.to_be_bytes());
+        if archive_flags & ARCHIVE_SOLID != 0 {
+            index.extend_from_slice(&entry.start.to_be_bytes());
+        }
+        index.extend_from_slice(&entry.meta.permissions.to_be_bytes());
+        index.extend_from_slice(&entry.meta.mtime.to_be_bytes());
+    }
+    index.extend_from_slice(&offset.to_be_bytes());
+    index.extend_from_slice(&crc32(&index[..index.len() - 8]).to_be_bytes());
+    index.extend_from_slice(&TRAILER_MAGIC);
+    output.write_all(&index).map_err(|err| Error::Compress("could not write archive index".to_string(), err.to_string()))
+}
+
+/// Reads and validates the index of an archive. Every path is checked to stay inside the
+/// directory it is extracted to.
+pub fn read_index<R: Read + Seek>(archive: &mut R) -> Result<Vec<Entry>> {
+    read_archive(archive).map(|(entries, _, _)| entries)
+}
+
+/// the index of an archive, its offset and the archive flags
+fn read_archive<R: Read + Seek>(archive: &mut R) -> Result<(Vec<Entry>, u64, u8)> {
+    let mut prefix = [0u8; PREFIX_LEN as usize];
+    archive.seek(SeekFrom::Start(0))
+        .and_then(|_| archive.read_exact(&mut prefix))
+        .map_err(|err| Error::DeCompress("could not read archive".to_string(), err.to_string()))?;
+    if !is_archive(&prefix) {
+        return Err(Error::DeCompress("input is not a huf archive".to_string(), format!("starts with {:02x?}", &prefix[..4])));
+    }
+    if prefix[4] != ARCHIVE_VERSION || prefix[5] & !ARCHIVE_SOLID != 0 {
+        return Err(Error::DeCompress("unsupported archive version or flags".to_string(), format!("version {}, flags {:#04x}", prefix[4], prefix[5])));
+    }
+    let end = archive.seek(SeekFrom::End(0)).map_err(|err| Error::DeCompress("could not read archive".to_string(), err.to_string()))?;
+    if end < PREFIX_LEN + TRAILER_LEN + 4 {
+        return Err(Error::DeCompress("archive is truncated".to_string(), format!("{} bytes", end)));
+    }
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    archive.seek(SeekFrom::Start(end - TRAILER_LEN))
+        .and_then(|_| archive.read_exact(&mut trailer))
+        .map_err(|err| Error::DeCompress("could not read archive trailer".to_string(), err.to_string()))?;
+    if trailer[12..] != TRAILER_MAGIC {
+        return Err(Error::DeCompress("archive is truncated, its trailer is missing".to_string(), String::new()));
+    }
+    let offset = u64::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3], trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if offset < PREFIX_LEN || offset > end - TRAILER_LEN {
+        return Err(Error::DeCompress("invalid archive index offset".to_string(), format!("{offset}")));
+    }
+    let mut index = Vec::new();
+    archive.seek(SeekFrom::Start(offset))
+        .and_then(|_| archive.take(end - TRAILER_LEN - offset).read_to_end(&mut index))
+        .map_err(|err| Error::DeCompress("could not read archive index".to_string(), err.to_string()))?;
+    let stored = u32::from_be_bytes([trailer[8], trailer[9], trailer[10], trailer[11]]);
+    if crc32(&index) != stored {
+        return Err(Error::Integrity("archive index checksum mismatch".to_string(), format!("stored {:#010x}, computed {:#010x}", stored, crc32(&index))));
+    }
+    Ok((parse_index(&index, offset, prefix[5])?, offset, prefix[5]))
+}
+
+fn parse_index(index: &[u8], end: u64, archive_flags: u8) -> Result<Vec<Entry>> {
+    let mut rest = index;
+    let count = u32::from_be_bytes(take(&mut rest, 4)?.try_into().unwrap_or_default());
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let kind = match take(&mut rest, 1)?[0] {
+            0 => Kind::File,
+            1 => Kind::Dir,
+            kind => return Err(Error::DeCompress("unknown archive entry type".to_string(), format!("{kind}"))),
+        };
+        let len = u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap_or_default()) as usize;
+        let path = String::from_utf8(take(&mut rest, len)?.to_vec()).map_err(|err| Error::DeCompress("archive path is not utf-8".to_string(), err.to_string()))?;
+        safe_path(&path)?;
+        let mut field = || take(&mut rest, 8).map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap_or_default()));
+        let (offset, length, size) = (field()?, field()?, field()?);
+        let start = if archive_flags & ARCHIVE_SOLID != 0 { field()? } else { 0 };
+        let permissions = u32::from_be_bytes(take(&mut rest, 4)?.try_into().unwrap_or_default());
+        let mtime = u64::from_be_bytes(take(&mut rest, 8)?.try_into().unwrap_or_default());
+        if offset.checked_add(length).is_none_or(|stop| stop > end) {
+            return Err(Error::DeCompress("archive member lies outside the archive".to_string(), path));
+        }
+        entries.push(Entry { path, kind, offset, length, size, start, meta: Meta { permissions, mtime } });
+    }
+    if !rest.is_empty() {
+        return Err(Error::DeCompress("unexpected bytes after the archive index".to_string(), format!("{}", rest.len())));
+    }
+    Ok(entries)
+}
+
+/// splits the next `len` bytes off `rest`
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if rest.len() < len {
+        return Err(Error::DeCompress("archive index is truncated".to_string(), String::new()));
+    }
+    let (head, tail) = rest.split_at(len);
+    *rest = tail;
+    Ok(head)
+}
+
+/// turns an archive path into a relative path, rejecting anything that could leave the
+/// extraction directory: absolute paths, '..', empty components and drive or '\' separators
+pub fn safe_path(path: &str) -> Result<PathBuf> {
+    let mut safe = PathBuf::new();
+    for part in path.split('/') {
+        if part.is_empty() || part == "." || part == ".." || part.contains(['\\', ':', '\0']) {
+            return Err(Error::DeCompress("unsafe path in archive".to_string(), path.to_string()));
+        }
+        safe.push(part);
+    }
+    Ok(safe)
+}
+
+/// the entries named by `selected`, a directory selects everything below it, nothing selects all
+pub fn select<'e>(entries: &'e [Entry], selected: &[String]) -> Result<Vec<&'e Entry>> {
+    if selected.is_empty() {
+        return Ok(entries.iter().collect());
+    }
+    let selected: Vec<&str> = selected.iter().map(|sel| sel.trim_end_matches('/')).collect();
+    for sel in selected.iter() {
+        if !entries.iter().any(|entry| matches(entry, sel)) {
+            return Err(Error::DeCompress("no such member in the archive".to_string(), sel.to_string()));
+        }
+    }
+    Ok(entries.iter().filter(|entry| selected.iter().any(|sel| matches(entry, sel))).collect())
+}
+
+fn matches(entry: &Entry, sel: &str) -> bool {
+    entry.path == sel || (entry.path.starts_with(sel) && entry.path[sel.len()..].starts_with('/'))
+}
+
+/// decompresses the file `entry` into `output`, returns the number of bytes written
+pub fn extract_member<R: Read + Seek, W: Write>(archive: &mut R, entry: &Entry, output: &mut W, decoder: Decoder) -> Result<u64> {
+    let mut window = Window { inner: output, skip: entry.start, left: entry.size };
+    decompress_member(archive, entry, &mut window, decoder)?;
+    if window.left != 0 {
+        return Err(Error::DeCompress(format!("member '{}' is shorter than its recorded size", entry.path), format!("{} bytes missing", window.left)));
+    }
+    Ok(entry.size)
+}
+
+/// decompresses the whole member `entry` belongs to, returns its header
+fn decompress_member<R: Read + Seek, W: Write>(archive: &mut R, entry: &Entry, output: &mut W, decoder: Decoder) -> Result<Header> {
+    archive.seek(SeekFrom::Start(entry.offset)).map_err(|err| Error::DeCompress("could not read archive member".to_string(), err.to_string()))?;
+    let mut member = archive.take(entry.length);
+    let header = Header::read(&mut member)?;
+    format::decompress_body(&mut member, output, &header, decoder, None, 1)?;
+    Ok(header)
+}
+
+/// the header and decompressed bytes of the member `entry` belongs to
+fn read_member<R: Read + Seek>(archive: &mut R, entry: &Entry, decoder: Decoder) -> Result<(Header, Vec<u8>)> {
+    let mut data = Vec::new();
+    let header = decompress_member(archive, entry, &mut data, decoder)?;
+    Ok((header, data))
+}
+
+/// Extracts the members named by `selected` (all if empty) below `dest` and restores their
+/// permissions and modification times. Returns the extracted entries.
+pub fn extract(archive: &Path, dest: &Path, selected: &[String], decoder: Decoder) -> Result<Vec<Entry>> {
+    let file = File::open(archive).map_err(|err| Error::DeCompress(format!("could not open archive '{}'", archive.display()), err.to_string()))?;
+    let mut reader = BufReader::new(file);
+    let (entries, _, archive_flags) = read_archive(&mut reader)?;
+    let chosen = select(&entries, selected)?;
+    let mut dirs = Vec::new();
+    //only members that hold more than one file are decompressed into memory, the rest is streamed
+    let mut files_at: HashMap<u64, usize> = HashMap::new();
+    if archive_flags & ARCHIVE_SOLID != 0 {
+        for entry in entries.iter().filter(|entry| entry.kind == Kind::File) {
+            *files_at.entry(entry.offset).or_insert(0) += 1;
+        }
+    }
+    //the last decompressed solid group, its files are written from memory
+    let mut group: Option<(u64, Vec<u8>)> = None;
+    for entry in chosen.iter() {
+        let target = dest.join(safe_path(&entry.path)?);
+        match entry.kind {
+            Kind::Dir => {
+                fs::create_dir_all(&target).map_err(|err| Error::Finalizing(format!("could not create directory '{}'", target.display()), err.to_string()))?;
+                dirs.push((target, entry.meta));
+            },
+            Kind::File => {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).map_err(|err| Error::Finalizing(format!("could not create directory '{}'", parent.display()), err.to_string()))?;
+                }
+                let shared = files_at.get(&entry.offset).is_some_and(|files| *files > 1);
+                if shared && group.as_ref().map(|(offset, _)| *offset) != Some(entry.offset) {
+                    group = Some((entry.offset, read_member(&mut reader, entry, decoder)?.1));
+                }
+                let out = File::create(&target).map_err(|err| Error::Finalizing(format!("could not create '{}'", target.display()), err.to_string()))?;
+                let mut out = BufWriter::new(out);
+                let result = match group.as_ref() {
+                    Some((_, data)) if shared => data.get(entry.start as usize..(entry.start + entry.size) as usize)
+                        .ok_or_else(|| Error::DeCompress(format!("member '{}' is shorter than its recorded size", entry.path), String::new()))
+                        .and_then(|bytes| out.write_all(bytes).map_err(|err| Error::Finalizing("could not write output".to_string(), err.to_string()))),
+                    _ => extract_member(&mut reader, entry, &mut out, decoder).map(|_| ()),
+                }.and_then(|_| out.flush().map_err(|err| Error::Finalizing("could not write output".to_string(), err.to_string())));
+                drop(out);
+                if result.is_err() {
+                    let _ = fs::remove_file(&target);
+                }
+                result?;
+                restore_meta(&target, entry.meta)?;
+            },
+        }
+    }
+    //directories last and deepest first, so their files could still be written
+    for (dir, meta) in dirs.iter().rev() {
+        restore_meta(dir, *meta)?;
+    }
+    Ok(chosen.into_iter().cloned().collect())
+}
+
+/// passes on `left` bytes after dropping the first `skip`, cuts one file out of a solid group
+struct Window<W: Write> {
+    inner: W,
+    skip: u64,
+    left: u64,
+}
+
+impl<W: Write> Write for Window<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let skipped = self.skip.min(buf.len() as u64) as usize;
+        self.skip -= skipped as u64;
+        let rest = &buf[skipped..];
+        let passed = self.left.min(rest.len() as u64) as usize;
+        self.inner.write_all(&rest[..passed])?;
+        self.left -= passed as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// a fresh directory below the system temp directory
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("huf_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn directory_round_trip() {
+        let dir = temp_dir("archive");
+        let project = dir.join("project");
+        fs::create_dir_all(project.join("src/empty")).unwrap();
+        fs::write(project.join("readme.md"), b"# project\nsome words, some more words").unwrap();
+        fs::write(project.join("src/main.rs"), b"fn main() {\n    println!(\"hi\");\n}\n").unwrap();
+        fs::write(project.join("src/blank"), b"").unwrap();
+        restore_meta(&project.join("readme.md"), Meta { permissions: 0o600, mtime: 1_234_567_890 }).unwrap();
+
+        let archive = dir.join("project.huf");
+        let entries = create(&archive, &[project.clone()], &Options::default(), 0, false).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, vec!["project", "project/readme.md", "project/src", "project/src/blank", "project/src/empty", "project/src/main.rs"]);
+        assert_eq!(read_index(&mut File::open(&archive).unwrap()).unwrap(), entries);
+
+        let out = dir.join("out");
+        extract(&archive, &out, &[], Decoder::Table).unwrap();
+        for file in ["readme.md", "src/main.rs", "src/blank"] {
+            assert_eq!(fs::read(out.join("project").join(file)).unwrap(), fs::read(project.join(file)).unwrap());
+        }
+        assert!(out.join("project/src/empty").is_dir());
+        let meta = file_meta(&out.join("project/readme.md")).unwrap();
+        assert_eq!(meta.mtime, 1_234_567_890);
+
+        //only the selected members
+        let some = dir.join("some");
+        let chosen = extract(&archive, &some, &["project/src/".to_string()], Decoder::Table).unwrap();
+        assert_eq!(chosen.len(), 4);
+        assert!(!some.join("project/readme.md").exists());
+        assert!(some.join("project/src/main.rs").exists());
+        assert!(extract(&archive, &some, &["project/missing".to_string()], Decoder::Table).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn add_and_delete() {
+        let dir = temp_dir("members");
+        let (first, second) = (dir.join("first.txt"), dir.join("second.txt"));
+        fs::write(&first, "the first member ".repeat(100)).unwrap();
+        fs::write(&second, "the second member ".repeat(200)).unwrap();
+        let archive = dir.join("members.huf");
+        add(&archive, &[first.clone()], &Options::default(), 0, false).unwrap();
+        let entries = add(&archive, &[second.clone()], &Options::default(), 0, false).unwrap();
+        assert_eq!(list(&archive).unwrap(), entries);
+        assert_eq!(entries.iter().map(|entry| entry.path.as_str()).collect::<Vec<&str>>(), vec!["first.txt", "second.txt"]);
+        assert_eq!(entries[1].size, 3600);
+        assert!(entries[1].length < entries[1].size);
+
+        //adding a path again replaces the member
+        fs::write(&first, "changed").unwrap();
+        let entries = add(&archive, &[first.clone()], &Options::default(), 0, false).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.path.as_str()).collect::<Vec<&str>>(), vec!["second.txt", "first.txt"]);
+        let mut out = Vec::new();
+        let mut reader = File::open(&archive).unwrap();
+        extract_member(&mut reader, &entries[1], &mut out, Decoder::Table).unwrap();
+        assert_eq!(out, b"changed");
+
+        //deleting compacts away the replaced and the deleted member
+        let before = fs::metadata(&archive).unwrap().len();
+        let removed = delete(&archive, &["second.txt".to_string()], &Options::default(), Decoder::Table).unwrap();
+        assert_eq!(removed.len(), 1);
+        let entries = list(&archive).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(fs::metadata(&archive).unwrap().len() < before - entries[0].length);
+        out.clear();
+        extract_member(&mut File::open(&archive).unwrap(), &entries[0], &mut out, Decoder::Table).unwrap();
+        assert_eq!(out, b"changed");
+        assert!(delete(&archive, &["second.txt".to_string()], &Options::default(), Decoder::Table).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn solid_groups() {
+        let dir = temp_dir("solid");
+        let configs = dir.join("configs");
+        fs::create_dir_all(&configs).unwrap();
+        for i in 0..200 {
+            fs::write(configs.join(format!("service_{:03}.conf", i)), format!("name = service_{}\nport = {}\nenabled = true\n", i, 8000 + i)).unwrap();
+        }
+        fs::write(configs.join("logo.bin"), (0..5000u32).map(|i| (i * 7919 % 251) as u8).collect::<Vec<u8>>()).unwrap();
+        //larger than a block, it gets a member of its own and is streamed on extraction
+        let log: String = (0..1500).map(|i| format!("{} request served in {} ms\n", i, i % 97)).collect();
+        fs::write(configs.join("access.log"), &log).unwrap();
+        let (single, solid) = (dir.join("single.huf"), dir.join("solid.huf"));
+        let options = Options { block_size: 4096, ..Default::default() };
+        let separate = create(&single, &[configs.clone()], &options, 0, false).unwrap();
+        let entries = create(&solid, &[configs.clone()], &options, 0, true).unwrap();
+        //the small files share a few members
+        let members = |entries: &[Entry]| {
+            let mut groups: Vec<(u64, u64)> = entries.iter().filter(|entry| entry.path.ends_with(".conf")).map(|entry| (entry.offset, entry.length)).collect();
+            groups.sort();
+            groups.dedup();
+            (groups.len(), groups.iter().map(|(_, length)| *length).sum::<u64>())
+        };
+        let (count, bytes) = members(&entries);
+        assert!(count > 1 && count < 10);
+        //alone they are stored, as a table costs more than it saves
+        assert!(bytes * 2 < members(&separate).1);
+
+        let big = entries.iter().find(|entry| entry.path == "configs/access.log").unwrap();
+        assert!(big.size > 4096 * 4);
+        assert_eq!(entries.iter().filter(|entry| entry.offset == big.offset).count(), 1);
+
+        let out = dir.join("out");
+        extract(&solid, &out, &[], Decoder::Table).unwrap();
+        assert_eq!(fs::read_to_string(out.join("configs/access.log")).unwrap(), log);
+        for entry in entries.iter().filter(|entry| entry.kind == Kind::File) {
+            let name = entry.path.trim_start_matches("configs/");
+            assert_eq!(fs::read(out.join(&entry.path)).unwrap(), fs::read(configs.join(name)).unwrap());
+        }
+        let mut one = Vec::new();
+        let member = entries.iter().find(|entry| entry.path == "configs/service_150.conf").unwrap();
+        extract_member(&mut File::open(&solid).unwrap(), member, &mut one, Decoder::Table).unwrap();
+        assert_eq!(one, fs::read(configs.join("service_150.conf")).unwrap());
+
+        //deleting from a group compresses the rest of it again, with the options given
+        let plain = dir.join("plain.huf");
+        fs::copy(&solid, &plain).unwrap();
+        let deleted = ["configs/service_100.conf".to_string(), "configs/logo.bin".to_string()];
+        delete(&plain, &deleted, &Options::default(), Decoder::Table).unwrap();
+        let lz = Options { lz: Some(Default::default()), ..Default::default() };
+        delete(&solid, &deleted, &lz, Decoder::Table).unwrap();
+        let (with_lz, without) = (fs::metadata(&solid).unwrap().len(), fs::metadata(&plain).unwrap().len());
+        //only the group that lost a file is coded again
+        assert!(with_lz + 1024 < without, "{} and {} bytes", with_lz, without);
+        let left = list(&solid).unwrap();
+        assert_eq!(left.len(), entries.len() - 2);
+        let again = dir.join("again");
+        extract(&solid, &again, &[], Decoder::Table).unwrap();
+        assert!(!again.join("configs/service_100.conf").exists());
+        assert_eq!(fs::read(again.join("configs/service_101.conf")).unwrap(), fs::read(configs.join("service_101.conf")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        for path in ["../evil", "/etc/passwd", "a/../../b", "a//b", "c:/windows", "a\\..\\b", "."] {
+            assert!(safe_path(path).is_err(), "{} was accepted", path);
+        }
+        assert_eq!(safe_path("a/b.txt").unwrap(), Path::new("a").join("b.txt"));
+
+        //an index with a traversing path is refused before anything is extracted
+        let entry = Entry { path: "../evil".to_string(), kind: Kind::Dir, offset: PREFIX_LEN, length: 0, size: 0, start: 0, meta: Meta { permissions: 0o755, mtime: 0 } };
+        let mut archive = ARCHIVE_MAGIC.to_vec();
+        archive.extend_from_slice(&[ARCHIVE_VERSION, 0]);
+        write_index(&mut archive, &[entry], PREFIX_LEN, 0).unwrap();
+        assert!(read_index(&mut std::io::Cursor::new(archive)).is_err());
+    }
+
+    #[test]
+    fn damaged_index() {
+        let mut archive = ARCHIVE_MAGIC.to_vec();
+        archive.extend_from_slice(&[ARCHIVE_VERSION, 0]);
+        let entry = Entry { path: "dir".to_string(), kind: Kind::Dir, offset: PREFIX_LEN, length: 0, size: 0, start: 0, meta: Meta { permissions: 0o755, mtime: 0 } };
+        write_index(&mut archive, &[entry], PREFIX_LEN, 0).unwrap();
+        assert_eq!(read_index(&mut std::io::Cursor::new(archive.clone())).unwrap().len(), 1);
+        archive[PREFIX_LEN as usize + 6] ^= 1;
+        assert!(read_index(&mut std::io::Cursor::new(archive.clone())).is_err());
+        assert!(read_index(&mut std::io::Cursor::new(&archive[..archive.len() - 3])).is_err());
+    }
+}
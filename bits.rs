@@ -0,0 +1,332 @@
+use crate::error::{Result, Error};
+use std::io::{Read, Write};
+
+const WRITE_BUFFER: usize = 8 * 1024;
+
+/// The order in which bits are packed into a byte.
+/// `Lsb` fills every byte from its least significant bit up (like DEFLATE),
+/// `Msb` from its most significant bit down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitOrder {
+    Lsb,
+    #[allow(dead_code)]
+    Msb,
+}
+
+/// reverses the lowest `len` bits of `code`
+pub fn reverse_bits(code: u64, len: u32) -> u64 {
+    if len == 0 {
+        return 0;
+    }
+    code.reverse_bits() >> (64 - len)
+}
+
+fn mask(n: u32) -> u64 {
+    if n >= 64 { u64::MAX } else { (1u64 << n) - 1 }
+}
+
+/// Writes values of up to 64 bits into any `Write`.
+/// `read_bits` of a `BitReader` with the same order returns the values as they were written.
+pub struct BitWriter<W: Write> {
+    inner: W,
+    order: BitOrder,
+    acc: u64,
+    count: u32,
+    buffer: Vec<u8>,
+    written: u64,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(inner: W, order: BitOrder) -> Self {
+        Self {
+            inner,
+            order,
+            acc: 0,
+            count: 0,
+            buffer: Vec::with_capacity(WRITE_BUFFER),
+            written: 0,
+        }
+    }
+
+    /// writes the lowest `n` bits of `value`
+    pub fn write_bits(&mut self, value: u64, n: u32) -> Result<()> {
+        if n > 32 {
+            //keep the accumulator from overflowing
+            return match self.order {
+                BitOrder::Lsb => {
+                    self.write_bits(value & mask(32), 32)?;
+                    self.write_bits(value >> 32, n - 32)
+                },
+                BitOrder::Msb => {
+                    self.write_bits(value >> 32, n - 32)?;
+                    self.write_bits(value & mask(32), 32)
+                },
+            };
+        }
+        let value = value & mask(n);
+        match self.order {
+            BitOrder::Lsb => {
+                self.acc |= value << self.count;
+                self.count += n;
+                while self.count >= 8 {
+                    self.buffer.push(self.acc as u8);
+                    self.acc >>= 8;
+                    self.count -= 8;
+                }
+            },
+            BitOrder::Msb => {
+                self.acc = (self.acc << n) | value;
+                self.count += n;
+                while self.count >= 8 {
+                    self.buffer.push((self.acc >> (self.count - 8)) as u8);
+                    self.count -= 8;
+                }
+                self.acc &= mask(self.count);
+            },
+        }
+        self.written += n as u64;
+        if self.buffer.len() >= WRITE_BUFFER {
+            self.flush_buffer()?;
+        }
+        Ok(())
+    }
+
+    /// writes a prefix code so its most significant bit comes first in the stream,
+    /// no matter the bit order
+    pub fn write_code(&mut self, code: u64, len: u32) -> Result<()> {
+        match self.order {
+            BitOrder::Lsb => self.write_bits(reverse_bits(code, len), len),
+            BitOrder::Msb => self.write_bits(code, len),
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) -> Result<()> {
+        self.write_bits(byte as u64, 8)
+    }
+
+    /// pads with zero bits up to the next byte boundary
+    pub fn align(&mut self) -> Result<()> {
+        let pad = (8 - self.count % 8) % 8;
+        self.write_bits(0, pad)
+    }
+
+    /// number of bits written so far, including padding
+    pub fn bits_written(&self) -> u64 {
+        self.written
+    }
+
+    fn flush_buffer(&mut self) -> Result<()> {
+        self.inner.write_all(&self.buffer).map_err(|err| Error::Encoding("could not write bits".to_string(), err.to_string()))?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// pads the last byte, flushes everything and hands back the inner writer
+    pub fn finish(mut self) -> Result<W> {
+        self.align()?;
+        self.flush_buffer()?;
+        self.inner.flush().map_err(|err| Error::Encoding("could not flush bits".to_string(), err.to_string()))?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads values of up to 64 bits from any `Read`.
+/// The inner reader is consumed one byte at a time, `peek_bits` reads ahead at most 4 bytes.
+pub struct BitReader<R: Read> {
+    inner: R,
+    order: BitOrder,
+    acc: u64,
+    count: u32,
+    eof: bool,
+    read: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(inner: R, order: BitOrder) -> Self {
+        Self {
+            inner,
+            order,
+            acc: 0,
+            count: 0,
+            eof: false,
+            read: 0,
+        }
+    }
+
+    /// tries to hold at least `n` (at most 32) bits, stops early at the end of the input
+    fn refill(&mut self, n: u32) -> Result<()> {
+        while self.count < n && !self.eof {
+            let mut byte = [0u8; 1];
+            let got = loop {
+                match self.inner.read(&mut byte) {
+                    Ok(got) => break got,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(Error::DeCompress("could not read bits".to_string(), err.to_string())),
+                }
+            };
+            if got == 0 {
+                self.eof = true;
+                break;
+            }
+            match self.order {
+                BitOrder::Lsb => self.acc |= (byte[0] as u64) << self.count,
+                BitOrder::Msb => self.acc = (self.acc << 8) | byte[0] as u64,
+            }
+            self.count += 8;
+        }
+        Ok(())
+    }
+
+    /// returns the next `n` (at most 32) bits without consuming them,
+    /// bits past the end of the input read as zero
+    pub fn peek_bits(&mut self, n: u32) -> Result<u64> {
+        self.refill(n)?;
+        let value = match self.order {
+            BitOrder::Lsb => self.acc & mask(n),
+            BitOrder::Msb if self.count >= n => (self.acc >> (self.count - n)) & mask(n),
+            BitOrder::Msb => (self.acc << (n - self.count)) & mask(n),
+        };
+        Ok(value)
+    }
+
+    /// drops `n` (at most 32) bits, fails if the input has less left
+    pub fn consume(&mut self, n: u32) -> Result<()> {
+        self.refill(n)?;
+        if self.count < n {
+            return Err(Error::DeCompress("unexpected end of bit stream".to_string(), format!("after {} bits", self.read + self.count as u64)));
+        }
+        self.count -= n;
+        match self.order {
+            BitOrder::Lsb => self.acc = if n >= 64 { 0 } else { self.acc >> n },
+            BitOrder::Msb => self.acc &= mask(self.count),
+        }
+        self.read += n as u64;
+        Ok(())
+    }
+
+    /// reads `n` (at most 64) bits
+    pub fn read_bits(&mut self, n: u32) -> Result<u64> {
+        if n > 32 {
+            return match self.order {
+                BitOrder::Lsb => {
+                    let low = self.read_bits(32)?;
+                    Ok(low | (self.read_bits(n - 32)? << 32))
+                },
+                BitOrder::Msb => {
+                    let high = self.read_bits(n - 32)?;
+                    Ok((high << 32) | self.read_bits(32)?)
+                },
+            };
+        }
+        let value = self.peek_bits(n)?;
+        self.consume(n)?;
+        Ok(value)
+    }
+
+    pub fn read_bit(&mut self) -> Result<u8> {
+        Ok(self.read_bits(1)? as u8)
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read_bits(8)? as u8)
+    }
+
+    /// skips the bits up to the next byte boundary
+    pub fn align(&mut self) -> Result<()> {
+        let skip = ((8 - self.read % 8) % 8) as u32;
+        self.consume(skip)
+    }
+
+    /// true if every bit of the input has been consumed
+    pub fn at_end(&mut self) -> Result<bool> {
+        self.refill(1)?;
+        Ok(self.count == 0)
+    }
+
+    /// number of bits consumed so far
+    pub fn bits_read(&self) -> u64 {
+        self.read
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lsb_packing() {
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::Lsb);
+        writer.write_bits(0b1, 1).unwrap();
+        writer.write_bits(0b10, 2).unwrap();
+        writer.write_bits(0xabc, 12).unwrap();
+        assert_eq!(writer.bits_written(), 15);
+        let bytes = writer.finish().unwrap();
+        assert_eq!(bytes, vec![0b1110_0101, 0b0101_0101]);
+    }
+
+    #[test]
+    fn msb_packing() {
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::Msb);
+        writer.write_bits(0b1, 1).unwrap();
+        writer.write_bits(0b10, 2).unwrap();
+        writer.write_bits(0xabc, 12).unwrap();
+        let bytes = writer.finish().unwrap();
+        assert_eq!(bytes, vec![0b1101_0101, 0b0111_1000]);
+    }
+
+    #[test]
+    fn round_trip() {
+        for order in [BitOrder::Lsb, BitOrder::Msb] {
+            let values: Vec<(u64, u32)> = (1..=64u32).map(|n| (0x9e37_79b9_7f4a_7c15u64 & mask(n), n)).collect();
+            let mut writer = BitWriter::new(Vec::new(), order);
+            for (value, n) in values.iter() {
+                writer.write_bits(*value, *n).unwrap();
+            }
+            let bytes = writer.finish().unwrap();
+            let mut reader = BitReader::new(bytes.as_slice(), order);
+            for (value, n) in values.iter() {
+                assert_eq!(reader.read_bits(*n).unwrap(), *value);
+            }
+            reader.align().unwrap();
+            assert!(reader.read_bit().is_err());
+        }
+    }
+
+    #[test]
+    fn codes_come_first_bit_first() {
+        for order in [BitOrder::Lsb, BitOrder::Msb] {
+            let mut writer = BitWriter::new(Vec::new(), order);
+            writer.write_code(0b110, 3).unwrap();
+            writer.write_code(0b01, 2).unwrap();
+            let bytes = writer.finish().unwrap();
+            let mut reader = BitReader::new(bytes.as_slice(), order);
+            let bits: Vec<u8> = (0..5).map(|_| reader.read_bit().unwrap()).collect();
+            assert_eq!(bits, vec![1, 1, 0, 0, 1]);
+        }
+    }
+
+    #[test]
+    fn peek_and_align() {
+        let bytes = [0b1010_1100u8, 0xff];
+        let mut reader = BitReader::new(&bytes[..], BitOrder::Lsb);
+        assert_eq!(reader.peek_bits(4).unwrap(), 0b1100);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b100);
+        reader.align().unwrap();
+        assert_eq!(reader.bits_read(), 8);
+        //past the end peeking pads with zeros, consuming fails
+        assert_eq!(reader.peek_bits(12).unwrap(), 0xff);
+        assert!(reader.consume(12).is_err());
+
+        let mut reader = BitReader::new(&bytes[..], BitOrder::Msb);
+        assert_eq!(reader.read_bits(4).unwrap(), 0b1010);
+        assert_eq!(reader.peek_bits(16).unwrap(), 0b1100_1111_1111_0000);
+    }
+
+    #[test]
+    fn reverse() {
+        assert_eq!(reverse_bits(0b110, 3), 0b011);
+        assert_eq!(reverse_bits(0b1, 1), 0b1);
+        assert_eq!(reverse_bits(0, 0), 0);
+        assert_eq!(reverse_bits(1, 64), 1 << 63);
+    }
+}
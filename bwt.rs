@@ -0,0 +1,243 @@
+use crate::error::{Result, Error};
+use crate::coder::EntropyCoder;
+use crate::stream::MAX_BLOCK_SIZE;
+
+/// the two symbols a run of zeros is written with, in bijective base 2
+const RUN_A: u32 = 0;
+const RUN_B: u32 = 1;
+/// the other move-to-front ranks are shifted past the run symbols
+const FIRST_RANK: u32 = 1;
+
+/// Sorts the suffixes of `data` by prefix doubling, a suffix that ends earlier sorts first.
+/// Every round orders by the rank of the first `k` bytes and then of the `k` bytes after them,
+/// both with a counting sort, so it takes O(n log n).
+fn suffix_array(data: &[u8]) -> Vec<u32> {
+    let n = data.len();
+    let mut sa: Vec<u32> = (0..n as u32).collect();
+    sa.sort_by_key(|pos| data[*pos as usize]);
+    //rank 0 is left for the end of the data
+    let mut rank: Vec<u32> = data.iter().map(|b| *b as u32 + 1).collect();
+    let mut next = vec![0u32; n];
+    let mut counts = vec![0usize; n.max(256) + 2];
+    let mut k = 1;
+    while k < n {
+        //ordered by the second half: the suffixes too short for it, then the others as `sa` has them
+        let mut second: Vec<u32> = (n - k..n).map(|pos| pos as u32).collect();
+        second.extend(sa.iter().filter(|pos| **pos as usize >= k).map(|pos| *pos - k as u32));
+        counts.iter_mut().for_each(|count| *count = 0);
+        for pos in second.iter() {
+            counts[rank[*pos as usize] as usize + 1] += 1;
+        }
+        for idx in 1..counts.len() {
+            counts[idx] += counts[idx - 1];
+        }
+        for pos in second.iter() {
+            let slot = &mut counts[rank[*pos as usize] as usize];
+            sa[*slot] = *pos;
+            *slot += 1;
+        }
+        let key = |pos: usize| (rank[pos], if pos + k < n { rank[pos + k] } else { 0 });
+        next[sa[0] as usize] = 1;
+        for idx in 1..n {
+            let differs = key(sa[idx] as usize) != key(sa[idx - 1] as usize);
+            next[sa[idx] as usize] = next[sa[idx - 1] as usize] + differs as u32;
+        }
+        std::mem::swap(&mut rank, &mut next);
+        if rank[sa[n - 1] as usize] as usize == n {
+            break;
+        }
+        k *= 2;
+    }
+    sa
+}
+
+/// The Burrows-Wheeler transform of `data` followed by an end marker that sorts before every
+/// byte: the byte before every sorted suffix. The marker itself is left out, returned is
+/// where it would be, between 1 and `data.len()`.
+fn transform(data: &[u8]) -> (Vec<u8>, usize) {
+    let mut last = Vec::with_capacity(data.len());
+    let mut primary = 0;
+    if let Some(b) = data.last() {
+        //the empty suffix sorts first, the whole data comes before it
+        last.push(*b);
+    }
+    for (idx, pos) in suffix_array(data).into_iter().enumerate() {
+        match pos {
+            0 => primary = idx + 1,
+            pos => last.push(data[pos as usize - 1]),
+        }
+    }
+    (last, primary)
+}
+
+/// reverts `transform` by walking from every suffix to the one a byte longer
+fn inverse(last: &[u8], primary: usize) -> Result<Vec<u8>> {
+    let n = last.len();
+    if (n == 0 && primary != 0) || (n > 0 && (primary == 0 || primary > n)) {
+        return Err(Error::DeCompress("invalid position of the end marker".to_string(), format!("{primary}")));
+    }
+    let at = |row: usize| if row < primary { last[row] } else { last[row - 1] };
+    //the first row of every byte among the sorted suffixes, row 0 is the empty one
+    let mut first = [0usize; 256];
+    for b in last.iter() {
+        first[*b as usize] += 1;
+    }
+    let mut row = 1;
+    for count in first.iter_mut() {
+        let rows = *count;
+        *count = row;
+        row += rows;
+    }
+    let mut lf = vec![0u32; n + 1];
+    for (row, next) in lf.iter_mut().enumerate() {
+        if row != primary {
+            let slot = &mut first[at(row) as usize];
+            *next = *slot as u32;
+            *slot += 1;
+        }
+    }
+    let mut data = vec![0u8; n];
+    let mut row = 0;
+    for pos in (0..n).rev() {
+        if row == primary {
+            return Err(Error::DeCompress("end marker reached before the start of the block".to_string(), String::new()));
+        }
+        data[pos] = at(row);
+        row = lf[row] as usize;
+    }
+    Ok(data)
+}
+
+/// Move-to-front ranks of `data` with runs of rank 0 written as their length in bijective
+/// base 2 with `RUN_A` and `RUN_B`, the other ranks shifted by `FIRST_RANK`.
+fn mtf_rle(data: &[u8]) -> Vec<u32> {
+    let mut order: Vec<u8> = (0..=255).collect();
+    let mut symbols = Vec::with_capacity(data.len());
+    let mut zeros = 0usize;
+    for b in data.iter() {
+        let rank = order.iter().position(|other| other == b).unwrap_or(0);
+        if rank == 0 {
+            zeros += 1;
+            continue;
+        }
+        push_run(&mut symbols, zeros);
+        zeros = 0;
+        order[..=rank].rotate_right(1);
+        symbols.push(rank as u32 + FIRST_RANK);
+    }
+    push_run(&mut symbols, zeros);
+    symbols
+}
+
+fn push_run(symbols: &mut Vec<u32>, mut run: usize) {
+    while run > 0 {
+        if run & 1 == 1 {
+            symbols.push(RUN_A);
+            run = (run - 1) / 2;
+        } else {
+            symbols.push(RUN_B);
+            run = (run - 2) / 2;
+        }
+    }
+}
+
+/// reverts `mtf_rle`
+fn un_mtf_rle(symbols: &[u32]) -> Result<Vec<u8>> {
+    let mut order: Vec<u8> = (0..=255).collect();
+    let mut data = Vec::with_capacity(symbols.len());
+    let (mut run, mut digit) = (0usize, 1usize);
+    for sym in symbols.iter() {
+        if *sym == RUN_A || *sym == RUN_B {
+            //no block is larger, so a longer run can only come from corrupted data
+            run = run.checked_add(digit << *sym)
+                .filter(|run| data.len() + run <= MAX_BLOCK_SIZE)
+                .ok_or_else(|| Error::DeCompress("run of zeros is too long".to_string(), String::new()))?;
+            digit <<= 1;
+            continue;
+        }
+        if data.len() + run >= MAX_BLOCK_SIZE {
+            return Err(Error::DeCompress("block is larger than any block can be".to_string(), String::new()));
+        }
+        data.extend(std::iter::repeat_n(order[0], run));
+        (run, digit) = (0, 1);
+        let rank = (*sym - FIRST_RANK) as usize;
+        if rank > 255 {
+            return Err(Error::DeCompress("invalid move-to-front rank".to_string(), format!("{sym}")));
+        }
+        order[..=rank].rotate_right(1);
+        data.push(order[0]);
+    }
+    data.extend(std::iter::repeat_n(order[0], run));
+    Ok(data)
+}
+
+/// Block sorting like bzip2: the Burrows-Wheeler transform of `data` brings bytes with the same
+/// context together, move-to-front turns them into mostly small ranks and the runs of rank 0 are
+/// run length coded, what is left is coded by `coder`.
+///
+/// Written are 32 bits position of the end marker (see `transform`), then what `coder` writes.
+pub fn encode(data: &[u8], coder: &dyn EntropyCoder) -> Result<Vec<u8>> {
+    if data.len() > u32::MAX as usize - 1 {
+        return Err(Error::Compress("block is too large for the transform".to_string(), format!("{}", data.len())));
+    }
+    let (last, primary) = transform(data);
+    let symbols = mtf_rle(&last);
+    let mut encoded = (primary as u32).to_le_bytes().to_vec();
+    encoded.extend_from_slice(&coder.encode(&symbols)?);
+    Ok(encoded)
+}
+
+/// Decodes a stream written by `encode` with the same kind of `coder`.
+pub fn decode(bytes: &[u8], coder: &dyn EntropyCoder) -> Result<Vec<u8>> {
+    if bytes.len() < 4 {
+        return Err(Error::DeCompress("block is too short".to_string(), format!("{} bytes", bytes.len())));
+    }
+    let primary = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let last = un_mtf_rle(&coder.decode(&bytes[4..])?)?;
+    inverse(&last, primary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::coder::Coder;
+    use crate::huffman::Decoder;
+
+    #[test]
+    fn sorts_suffixes() {
+        for data in [&b"banana"[..], b"mississippi", b"aaaaaaa", b"abababab", b"", b"z"] {
+            let mut expected: Vec<u32> = (0..data.len() as u32).collect();
+            expected.sort_by_key(|pos| &data[*pos as usize..]);
+            assert_eq!(suffix_array(data), expected);
+            let (last, primary) = transform(data);
+            assert_eq!(inverse(&last, primary).unwrap(), data);
+        }
+        assert_eq!(transform(b"banana"), (b"annbaa".to_vec(), 4));
+        let runs = [vec![5u8; 1000], b"xyz".to_vec(), vec![0; 3]].concat();
+        assert_eq!(un_mtf_rle(&mtf_rle(&runs)).unwrap(), runs);
+    }
+
+    #[test]
+    fn beats_order0_on_text() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let data = &text[..128 * 1024];
+        let coder = Coder::Huffman.build(0, Decoder::Table);
+        let encoded = encode(data, coder.as_ref()).unwrap();
+        assert_eq!(decode(&encoded, coder.as_ref()).unwrap(), data);
+        assert!(encoded.len() < data.len() * 35 / 100, "{} bytes", encoded.len());
+        let rans = Coder::Rans.build(0, Decoder::Table);
+        for data in [&b""[..], b"a", &[0u8, 255, 0, 255]] {
+            let limited = Coder::Huffman.build(12, Decoder::Tree);
+            assert_eq!(decode(&encode(data, limited.as_ref()).unwrap(), limited.as_ref()).unwrap(), data);
+            assert_eq!(decode(&encode(data, rans.as_ref()).unwrap(), rans.as_ref()).unwrap(), data);
+        }
+        let mut corrupt = encode(b"abracadabra", coder.as_ref()).unwrap();
+        corrupt[0] = 20;
+        assert!(decode(&corrupt, coder.as_ref()).is_err());
+        //a run longer than any block is rejected before it is written out
+        let mut huge = 1u32.to_le_bytes().to_vec();
+        huge.extend_from_slice(&coder.encode(&[RUN_B; 40]).unwrap());
+        assert!(decode(&huge, coder.as_ref()).is_err());
+        assert!(un_mtf_rle(&[RUN_B; 28]).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::error::{Result, Error};
+use crate::huffman::{self, Decoder, Huffman, Mode};
+use crate::bits::{BitOrder, BitReader, BitWriter};
+use crate::stream::MAX_BLOCK_SIZE;
+
+/// rANS states stay in [`RANS_LOW`, 2^63), 32 bits are moved out or in at a time
+const RANS_LOW: u64 = 1 << 31;
+/// precision of the symbol frequencies, raised if there are too many symbols for it
+const PROB_BITS: u32 = 15;
+const MAX_PROB_BITS: u32 = 28;
+
+/// Turns the symbols of a block into bytes and back, the last step of every pipeline
+/// that ends in a sequence of symbols.
+pub trait EntropyCoder {
+    fn encode(&self, symbols: &[u32]) -> Result<Vec<u8>>;
+    /// decodes everything `encode` wrote, `bytes` must not hold anything after it
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u32>>;
+}
+
+/// the entropy coders a file can be written with, recorded in its header
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Coder {
+    /// canonical prefix codes, see `Prefix`
+    #[default]
+    Huffman,
+    /// range asymmetric numeral systems, see `Rans`
+    Rans,
+}
+
+impl Coder {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Coder::Huffman => 0,
+            Coder::Rans => 1,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(Coder::Huffman),
+            1 => Ok(Coder::Rans),
+            _ => Err(Error::DeCompress("unknown entropy coder".to_string(), format!("{byte}"))),
+        }
+    }
+
+    /// the coder, `max_len` and `decoder` only apply to huffman codes
+    pub fn build(self, max_len: u8, decoder: Decoder) -> Box<dyn EntropyCoder> {
+        match self {
+            Coder::Huffman => Box::new(Prefix { max_len, decoder }),
+            Coder::Rans => Box::new(Rans),
+        }
+    }
+}
+
+/// Huffman codes with the table in front, see `Huffman::encode_symbols`.
+/// Every code is a whole number of bits, which costs up to a bit per symbol on skewed data.
+pub struct Prefix {
+    /// longest allowed code in bits, 0 for no limit
+    pub max_len: u8,
+    pub decoder: Decoder,
+}
+
+impl EntropyCoder for Prefix {
+    fn encode(&self, symbols: &[u32]) -> Result<Vec<u8>> {
+        let mut huffman = Huffman::with_symbols(Mode::Bytes, symbols, self.max_len)?;
+        huffman.encode_symbols(symbols)?;
+        let mut encoded = Vec::new();
+        huffman.read_bytes_into(&mut encoded);
+        Ok(encoded)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u32>> {
+        let mut huffman = Huffman::from_bytes(bytes)?;
+        huffman.set_decoder(self.decoder);
+        huffman.decode_symbols()
+    }
+}
+
+/// Static rANS: every symbol takes the share of the coder state its frequency has, so
+/// a symbol costs close to its information content, fractions of a bit included.
+///
+/// Written are 5 bits frequency precision, 32 bits number of symbols, each in utf-8
+/// (see `huffman::write_symbol`) with its frequency minus 1, 64 bits number of coded
+/// symbols, then byte aligned the final state as 8 bytes and the 4 byte words the
+/// encoder moved out, all little endian.
+pub struct Rans;
+
+/// (symbol, first slot, frequency) of every symbol, ordered by symbol
+type Model = Vec<(u32, u64, u64)>;
+
+/// frequencies that add up to `1 << bits`, every symbol keeps at least 1
+fn normalize(counts: &[(u32, u64)], bits: u32) -> Model {
+    let total = 1u64 << bits;
+    let count: u64 = counts.iter().map(|(_, count)| *count).sum();
+    let mut freqs: Vec<u64> = counts.iter().map(|(_, c)| ((*c as u128 * total as u128 / count as u128) as u64).max(1)).collect();
+    let mut sum: u64 = freqs.iter().sum();
+    //the most frequent symbols absorb the rounding, they lose the least by it
+    let mut order: Vec<usize> = (0..freqs.len()).collect();
+    order.sort_by(|a, b| freqs[*b].cmp(&freqs[*a]));
+    if sum < total {
+        freqs[order[0]] += total - sum;
+        sum = total;
+    }
+    while sum > total {
+        for idx in order.iter() {
+            if sum > total && freqs[*idx] > 1 {
+                freqs[*idx] -= 1;
+                sum -= 1;
+            }
+        }
+    }
+    let mut start = 0;
+    counts.iter().zip(freqs).map(|((sym, _), freq)| {
+        start += freq;
+        (*sym, start - freq, freq)
+    }).collect()
+}
+
+impl EntropyCoder for Rans {
+    fn encode(&self, symbols: &[u32]) -> Result<Vec<u8>> {
+        let mut counts = std::collections::BTreeMap::new();
+        for sym in symbols.iter() {
+            *counts.entry(*sym).or_insert(0u64) += 1;
+        }
+        let counts: Vec<(u32, u64)> = counts.into_iter().collect();
+        //at least two slots per symbol, so none is rounded up by much
+        let needed = (2 * counts.len()).next_power_of_two().trailing_zeros();
+        let bits = PROB_BITS.max(needed);
+        if bits > MAX_PROB_BITS {
+            return Err(Error::Encoding("too many distinct symbols".to_string(), format!("{}", counts.len())));
+        }
+        let model = if counts.is_empty() { Vec::new() } else { normalize(&counts, bits) };
+
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::Lsb);
+        writer.write_bits(bits as u64, 5)?;
+        writer.write_bits(model.len() as u64, 32)?;
+        for (sym, _, freq) in model.iter() {
+            huffman::write_symbol(&mut writer, *sym)?;
+            writer.write_bits(freq - 1, bits)?;
+        }
+        writer.write_bits(symbols.len() as u64, 64)?;
+        let mut encoded = writer.finish()?;
+
+        //rANS decodes in the reverse order it encodes
+        let mut state = RANS_LOW;
+        let mut words: Vec<u32> = Vec::with_capacity(symbols.len() / 4);
+        for sym in symbols.iter().rev() {
+            let (_, start, freq) = model[model.binary_search_by_key(sym, |(other, _, _)| *other).unwrap_or(0)];
+            if state >= ((RANS_LOW >> bits) << 32) * freq {
+                words.push(state as u32);
+                state >>= 32;
+            }
+            state = ((state / freq) << bits) + state % freq + start;
+        }
+        encoded.extend_from_slice(&state.to_le_bytes());
+        for word in words.iter().rev() {
+            encoded.extend_from_slice(&word.to_le_bytes());
+        }
+        Ok(encoded)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u32>> {
+        let mut reader = BitReader::new(bytes, BitOrder::Lsb);
+        let bits = reader.read_bits(5)? as u32;
+        if !(1..=MAX_PROB_BITS).contains(&bits) {
+            return Err(Error::DeCompress("invalid frequency precision".to_string(), format!("{bits}")));
+        }
+        let distinct = reader.read_bits(32)?;
+        let mut model: Model = Vec::new();
+        let mut start = 0u64;
+        for _ in 0..distinct {
+            let sym = huffman::read_symbol(&mut reader)?;
+            let freq = reader.read_bits(bits)? + 1;
+            if model.last().is_some_and(|(last, _, _)| *last >= sym) || start + freq > 1 << bits {
+                return Err(Error::DeCompress("invalid symbol frequencies".to_string(), format!("{sym}")));
+            }
+            model.push((sym, start, freq));
+            start += freq;
+        }
+        let count = reader.read_bits(64)?;
+        if (distinct > 0 && start != 1 << bits) || (distinct == 0 && count > 0) || count > MAX_BLOCK_SIZE as u64 {
+            return Err(Error::DeCompress("symbol frequencies do not fit the coded symbols".to_string(), format!("{count}")));
+        }
+        reader.align()?;
+        let mut words = bytes[(reader.bits_read() / 8) as usize..].chunks(4);
+        let mut word = || -> Result<u64> {
+            match words.next() {
+                Some(word) if word.len() == 4 => Ok(u32::from_le_bytes([word[0], word[1], word[2], word[3]]) as u64),
+                _ => Err(Error::DeCompress("coded symbols end early".to_string(), String::new())),
+            }
+        };
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut state = word()? | word()? << 32;
+        let mask = (1u64 << bits) - 1;
+        let mut symbols = Vec::with_capacity((count as usize).min(bytes.len() * 8));
+        for _ in 0..count {
+            let slot = state & mask;
+            let (sym, start, freq) = model[model.partition_point(|(_, start, _)| *start <= slot) - 1];
+            symbols.push(sym);
+            state = freq * (state >> bits) + slot - start;
+            if state < RANS_LOW {
+                state = state << 32 | word()?;
+            }
+        }
+        //the encoder started from the lowest state, anything else means corrupted data
+        if state != RANS_LOW || words.next().is_some() {
+            return Err(Error::DeCompress("coded symbols do not end where they should".to_string(), String::new()));
+        }
+        Ok(symbols)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn both_coders() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let symbols: Vec<u32> = text[..64 * 1024].iter().map(|b| *b as u32).collect();
+        let skewed: Vec<u32> = (0..50_000u32).map(|i| if i % 97 == 0 { i % 5 + 1 } else { 0 }).collect();
+        let wide: Vec<u32> = (0..40_000u32).map(|i| (i * 7919) % 40_000).collect();
+        for coder in [Coder::Huffman, Coder::Rans] {
+            assert_eq!(Coder::from_byte(coder.to_byte()).unwrap(), coder);
+            let coder = coder.build(0, Decoder::Table);
+            for data in [&symbols[..], &skewed, &wide, &[], &[0x10_ffff], &[3, 3, 3]] {
+                assert_eq!(coder.decode(&coder.encode(data).unwrap()).unwrap(), data);
+            }
+        }
+        //a prefix code needs a bit for every symbol, rANS gets close to the entropy
+        let prefix = Coder::Huffman.build(0, Decoder::Table).encode(&skewed).unwrap();
+        let rans = Rans.encode(&skewed).unwrap();
+        assert!(rans.len() * 4 < prefix.len(), "{} and {} bytes", rans.len(), prefix.len());
+        assert!(Rans.encode(&symbols).unwrap().len() < Coder::Huffman.build(0, Decoder::Table).encode(&symbols).unwrap().len());
+    }
+
+    #[test]
+    fn corrupted_rans() {
+        let encoded = Rans.encode(&[1, 2, 3, 1, 1, 1, 2, 4, 1, 1]).unwrap();
+        assert!(Rans.decode(&encoded[..encoded.len() - 1]).is_err());
+        let mut flipped = encoded.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 0x40;
+        assert!(Rans.decode(&flipped).is_err());
+        assert!(Coder::from_byte(7).is_err());
+    }
+}
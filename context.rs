@@ -0,0 +1,125 @@
+use crate::error::{Result, Error};
+use crate::huffman::{Huffman, Mode, read_symbol, write_symbol};
+use crate::bits::{BitOrder, BitReader, BitWriter};
+use crate::table::DecodeTable;
+use std::collections::{BTreeMap, HashMap};
+
+/// contexts followed by fewer symbols always use the shared table
+const MIN_CONTEXT: usize = 32;
+
+/// Order-1 coding: the code of a symbol depends on the symbol before it.
+/// Every context whose own table pays for itself gets one, rare contexts and the first symbol
+/// are merged into one shared table.
+///
+/// Written are 8 bits of symbol mode, 8 bits of code length limit, the lengths of the shared
+/// table, 32 bits number of context tables, each as its context symbol (utf-8) and its lengths
+/// (see `Huffman::write_lengths`), then 64 bits number of symbols and the byte aligned codes.
+pub fn encode(mode: Mode, symbols: &[u32], max_len: u8) -> Result<Vec<u8>> {
+    let mut followers: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    for pair in symbols.windows(2) {
+        followers.entry(pair[0]).or_default().push(pair[1]);
+    }
+    //a context gets its own table if that is smaller than coding it with the order-0 codes
+    let order0 = Huffman::with_symbols(mode, symbols, max_len)?;
+    let mut tables: BTreeMap<u32, Huffman> = BTreeMap::new();
+    for (ctx, next) in followers.iter().filter(|(_, next)| next.len() >= MIN_CONTEXT) {
+        let own = Huffman::with_symbols(mode, next, max_len)?;
+        let shared = own.cost_with(&order0).unwrap_or(u64::MAX);
+        if own.table_bits()? + own.cost_with(&own).unwrap_or(u64::MAX) < shared {
+            tables.insert(*ctx, own);
+        }
+    }
+    let rest = symbols.iter().take(1).copied()
+        .chain(followers.iter().filter(|(ctx, _)| !tables.contains_key(ctx)).flat_map(|(_, next)| next.iter().copied()))
+        .collect::<Vec<u32>>();
+    let shared = Huffman::with_symbols(mode, &rest, max_len)?;
+
+    let mut writer = BitWriter::new(Vec::new(), BitOrder::Lsb);
+    writer.write_byte(mode.to_byte())?;
+    writer.write_byte(max_len)?;
+    shared.write_lengths(&mut writer)?;
+    writer.write_bits(tables.len() as u64, 32)?;
+    for (ctx, table) in tables.iter() {
+        write_symbol(&mut writer, *ctx)?;
+        table.write_lengths(&mut writer)?;
+    }
+    writer.write_bits(symbols.len() as u64, 64)?;
+    writer.align()?;
+    let mut prev = None;
+    for sym in symbols.iter() {
+        let table = prev.and_then(|prev| tables.get(&prev)).unwrap_or(&shared);
+        let code = table.get_code(*sym)
+            .ok_or_else(|| Error::Encoding(format!("Could not get code for {}", sym), String::new()))?;
+        writer.write_code(code.bits, code.len as u32)?;
+        prev = Some(*sym);
+    }
+    writer.finish()
+}
+
+/// Decodes a stream written by `encode`, the previous symbol selects the table for the next.
+pub fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = BitReader::new(bytes, BitOrder::Lsb);
+    let mode = Mode::from_byte(reader.read_byte()?)?;
+    let max_len = reader.read_byte()?;
+    let shared = Huffman::from_lengths(&mut reader, mode, max_len)?.decode_table();
+    let num_tables = reader.read_bits(32)?;
+    let mut tables: HashMap<u32, DecodeTable> = HashMap::new();
+    for _ in 0..num_tables {
+        let ctx = read_symbol(&mut reader)?;
+        tables.insert(ctx, Huffman::from_lengths(&mut reader, mode, max_len)?.decode_table());
+    }
+    let count = reader.read_bits(64)?;
+    reader.align()?;
+    //every code takes at least one bit
+    if count > (bytes.len() as u64 * 8).saturating_sub(reader.bits_read()) {
+        return Err(Error::DeCompress("symbol count exceeds the coded data".to_string(), format!("{count}")));
+    }
+    let mut data = Vec::with_capacity(count as usize);
+    let mut prev = None;
+    for _ in 0..count {
+        let table = prev.and_then(|prev| tables.get(&prev)).unwrap_or(&shared);
+        let sym = table.decode(&mut reader)?;
+        mode.push_symbol(sym, &mut data);
+        prev = Some(sym);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(mode: Mode, data: &[u8]) -> (Vec<u8>, usize) {
+        let symbols = mode.symbols(data).unwrap().collect::<Vec<u32>>();
+        let encoded = encode(mode, &symbols, 0).unwrap();
+        (decode(&encoded).unwrap(), encoded.len())
+    }
+
+    #[test]
+    fn beats_order0_on_text() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let data = &text[..128 * 1024];
+        let (decoded, size) = round_trip(Mode::Bytes, data);
+        assert_eq!(decoded, data);
+        let mut order0 = Huffman::from_raw(data);
+        order0.create_tree().unwrap();
+        order0.codes();
+        order0.encode().unwrap();
+        let mut order0_bytes = Vec::new();
+        order0.read_bytes_into(&mut order0_bytes);
+        assert!(size < order0_bytes.len() * 9 / 10);
+    }
+
+    #[test]
+    fn small_and_unicode() {
+        for data in ["", "a", "qu", "Grüße aus Köln, quite quaint queues 日本語"] {
+            assert_eq!(round_trip(Mode::Text, data.as_bytes()).0, data.as_bytes());
+            assert_eq!(round_trip(Mode::Bytes, data.as_bytes()).0, data.as_bytes());
+        }
+        let data = "qu".repeat(500);
+        assert_eq!(round_trip(Mode::Bytes, data.as_bytes()).0, data.as_bytes());
+        //a truncated stream fails instead of giving short output
+        let symbols = Mode::Bytes.symbols(data.as_bytes()).unwrap().collect::<Vec<u32>>();
+        assert!(decode(&encode(Mode::Bytes, &symbols, 0).unwrap()[..20]).is_err());
+    }
+}
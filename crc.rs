@@ -0,0 +1,165 @@
+use std::io::{Read, Write, self};
+
+/// the reversed polynomial of CRC-32 as used by zip, gzip and png
+const POLY: u32 = 0xedb8_8320;
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+}
+
+/// CRC-32 that can be fed in pieces
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { state: u32::MAX }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for b in data {
+            self.state = TABLE[((self.state ^ *b as u32) & 0xff) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+/// CRC-32 of `data` in one go
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// largest prime below 2^16
+const ADLER_MOD: u32 = 65521;
+/// most bytes that can be summed before the sums could overflow
+const ADLER_CHUNK: usize = 5552;
+
+/// Adler-32 as used by zlib, can be fed in pieces
+#[derive(Clone, Copy, Debug)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    pub fn new() -> Self {
+        Self { a: 1, b: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(ADLER_CHUNK) {
+            for b in chunk {
+                self.a += *b as u32;
+                self.b += self.a;
+            }
+            self.a %= ADLER_MOD;
+            self.b %= ADLER_MOD;
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+/// passes reads through and keeps the checksum and number of the bytes read
+pub struct CrcReader<R: Read> {
+    inner: R,
+    pub crc: Crc32,
+    pub count: u64,
+}
+
+impl<R: Read> CrcReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, crc: Crc32::new(), count: 0 }
+    }
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let got = self.inner.read(buf)?;
+        self.crc.update(&buf[..got]);
+        self.count += got as u64;
+        Ok(got)
+    }
+}
+
+/// passes writes through and keeps the checksum and number of the bytes written
+pub struct CrcWriter<W: Write> {
+    inner: W,
+    pub crc: Crc32,
+    pub count: u64,
+}
+
+impl<W: Write> CrcWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, crc: Crc32::new(), count: 0 }
+    }
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc.update(&buf[..written]);
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_values() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+        let mut adler = Adler32::new();
+        adler.update(b"Wiki");
+        adler.update(b"pedia");
+        assert_eq!(adler.finish(), 0x11e6_0398);
+        let mut adler = Adler32::new();
+        adler.update(&vec![0xff; 100_000]);
+        assert_eq!(adler.finish(), 0x149a_302c);
+    }
+
+    #[test]
+    fn adapters() {
+        let mut reader = CrcReader::new(&b"123456789"[..]);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!((reader.crc.finish(), reader.count), (0xcbf4_3926, 9));
+
+        let mut writer = CrcWriter::new(Vec::new());
+        writer.write_all(&data).unwrap();
+        assert_eq!((writer.crc.finish(), writer.count), (0xcbf4_3926, 9));
+    }
+}
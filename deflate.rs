@@ -0,0 +1,661 @@
+use crate::error::{Result, Error};
+use crate::huffman::{Huffman, Mode};
+use crate::bits::{BitOrder, BitReader, BitWriter};
+use crate::crc::{Adler32, Crc32, crc32};
+use crate::lz77::{self, Settings, Token, bucket, bucket_base};
+use crate::table::DecodeTable;
+use std::io::{Read, Write};
+
+/// DEFLATE matches reach back at most 32 KiB
+pub const WINDOW: usize = 32 * 1024;
+/// input bytes per block, with the window in front of them
+const CHUNK: usize = 64 * 1024;
+/// decoded bytes are handed to the output once this many are buffered
+const FLUSH: usize = 256 * 1024;
+/// a stored block holds at most this many bytes
+const MAX_STORED: usize = 65535;
+const END_OF_BLOCK: u32 = 256;
+const MAX_BITS: u8 = 15;
+const MAX_CL_BITS: u8 = 7;
+
+const STORED: u64 = 0;
+const FIXED: u64 = 1;
+const DYNAMIC: u64 = 2;
+
+/// first match length and extra bits of the length codes 257 to 285
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+/// the order in which the code length code lengths are stored
+const CL_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+pub const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 8];
+const GZIP_HCRC: u8 = 0x02;
+const GZIP_EXTRA: u8 = 0x04;
+const GZIP_NAME: u8 = 0x08;
+const GZIP_COMMENT: u8 = 0x10;
+/// deflate with a 32 KiB window and the default level, the usual '78 9c'
+const ZLIB_HEADER: [u8; 2] = [0x78, 0x9c];
+const ZLIB_DICT: u8 = 0x20;
+
+/// the framing around a DEFLATE stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Container {
+    /// RFC 1952, what `gzip` reads and writes
+    Gzip,
+    /// RFC 1950, a two byte header and an Adler-32 checksum
+    Zlib,
+    /// the bare RFC 1951 stream
+    Raw,
+}
+
+impl Container {
+    /// the extension appended to compressed files
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Gzip => "gz",
+            Container::Zlib => "zz",
+            Container::Raw => "deflate",
+        }
+    }
+}
+
+/// checksums and size of the uncompressed data
+pub struct Sums {
+    pub crc: Crc32,
+    pub adler: Adler32,
+    pub size: u64,
+}
+
+impl Sums {
+    fn new() -> Self {
+        Self { crc: Crc32::new(), adler: Adler32::new(), size: 0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.crc.update(data);
+        self.adler.update(data);
+        self.size += data.len() as u64;
+    }
+}
+
+/// true if `prefix` starts like a gzip member
+pub fn is_gzip(prefix: &[u8]) -> bool {
+    prefix.len() >= GZIP_MAGIC.len() && prefix[..GZIP_MAGIC.len()] == GZIP_MAGIC
+}
+
+/// the length code (without the 257 offset), extra bits and extra of a match length
+fn length_code(len: usize) -> (u32, u32, u32) {
+    let code = LENGTH_BASE.iter().rposition(|base| *base as usize <= len).unwrap_or(0);
+    (code as u32, LENGTH_EXTRA[code] as u32, (len - LENGTH_BASE[code] as usize) as u32)
+}
+
+/// the fixed codes of RFC 1951 3.2.6
+fn fixed_tables() -> Result<(Huffman, Huffman)> {
+    let lit_len = (0..288u32).map(|sym| (sym, match sym {
+        0..=143 => 8,
+        144..=255 => 9,
+        256..=279 => 7,
+        _ => 8,
+    })).collect();
+    let distances = (0..30u32).map(|sym| (sym, 5)).collect();
+    Ok((Huffman::from_code_lengths(Mode::Bytes, lit_len)?, Huffman::from_code_lengths(Mode::Bytes, distances)?))
+}
+
+/// code lengths of `table` for the symbols `0..count`, 0 for symbols without code
+fn code_lengths(table: &Huffman, count: usize) -> Vec<u8> {
+    (0..count as u32).map(|sym| table.get_code(sym).map(|code| code.len).unwrap_or(0)).collect()
+}
+
+/// Run length codes a sequence of code lengths with the symbols 16 (repeat the previous length
+/// 3 to 6 times), 17 (3 to 10 zeros) and 18 (11 to 138 zeros), as (symbol, extra bits, extra).
+fn run_lengths(lengths: &[u8]) -> Vec<(u32, u32, u32)> {
+    let mut codes = Vec::new();
+    let mut pos = 0;
+    while pos < lengths.len() {
+        let len = lengths[pos];
+        let run = lengths[pos..].iter().take_while(|other| **other == len).count();
+        if len == 0 && run >= 11 {
+            let run = run.min(138);
+            codes.push((18, 7, (run - 11) as u32));
+            pos += run;
+        } else if len == 0 && run >= 3 {
+            codes.push((17, 3, (run - 3) as u32));
+            pos += run;
+        } else if len != 0 && run >= 4 {
+            //the first one is written as it is, the rest repeat it
+            codes.push((len as u32, 0, 0));
+            let repeat = (run - 1).min(6);
+            codes.push((16, 2, (repeat - 3) as u32));
+            pos += 1 + repeat;
+        } else {
+            codes.push((len as u32, 0, 0));
+            pos += 1;
+        }
+    }
+    codes
+}
+
+/// number of bits `tokens` take with the given tables, the end of block code included
+fn data_bits(tokens: &[Token], lit_len: &Huffman, distances: &Huffman) -> Option<u64> {
+    let mut bits = lit_len.get_code(END_OF_BLOCK)?.len as u64;
+    for token in tokens.iter() {
+        bits += match *token {
+            Token::Literal(b) => lit_len.get_code(b as u32)?.len as u64,
+            Token::Match { len, dist } => {
+                let (code, extra_bits, _) = length_code(len);
+                let (dist_code, dist_extra, _) = bucket(dist as u32 - 1);
+                (lit_len.get_code(257 + code)?.len + distances.get_code(dist_code)?.len) as u64 + (extra_bits + dist_extra) as u64
+            },
+        };
+    }
+    Some(bits)
+}
+
+/// the code tables of a dynamic block and its header
+struct Dynamic {
+    lit_len: Huffman,
+    distances: Huffman,
+    code_lengths: Huffman,
+    lengths: Vec<(u32, u32, u32)>,
+    hlit: usize,
+    hdist: usize,
+    hclen: usize,
+}
+
+impl Dynamic {
+    fn new(tokens: &[Token]) -> Result<Self> {
+        let mut lit_len = vec![END_OF_BLOCK];
+        let mut distances = Vec::new();
+        for token in tokens.iter() {
+            match *token {
+                Token::Literal(b) => lit_len.push(b as u32),
+                Token::Match { len, dist } => {
+                    lit_len.push(257 + length_code(len).0);
+                    distances.push(bucket(dist as u32 - 1).0);
+                },
+            }
+        }
+        let lit_len = Huffman::with_symbols(Mode::Bytes, &lit_len, MAX_BITS)?;
+        let distances = Huffman::with_symbols(Mode::Bytes, &distances, MAX_BITS)?;
+        let mut all = code_lengths(&lit_len, 286);
+        let hlit = all.iter().rposition(|len| *len > 0).map(|last| last + 1).unwrap_or(0).max(257);
+        all.truncate(hlit);
+        let dist_lengths = code_lengths(&distances, 30);
+        let hdist = dist_lengths.iter().rposition(|len| *len > 0).map(|last| last + 1).unwrap_or(0).max(1);
+        all.extend_from_slice(&dist_lengths[..hdist]);
+        let lengths = run_lengths(&all);
+        let code_lengths = Huffman::with_symbols(Mode::Bytes, &lengths.iter().map(|(sym, _, _)| *sym).collect::<Vec<u32>>(), MAX_CL_BITS)?;
+        let hclen = CL_ORDER.iter().rposition(|sym| code_lengths.get_code(*sym as u32).is_some()).map(|last| last + 1).unwrap_or(0).max(4);
+        Ok(Self { lit_len, distances, code_lengths, lengths, hlit, hdist, hclen })
+    }
+
+    fn header_bits(&self) -> u64 {
+        let mut bits = 5 + 5 + 4 + 3 * self.hclen as u64;
+        for (sym, extra_bits, _) in self.lengths.iter() {
+            bits += self.code_lengths.get_code(*sym).map(|code| code.len as u64).unwrap_or(0) + *extra_bits as u64;
+        }
+        bits
+    }
+
+    fn write_header<W: Write>(&self, writer: &mut BitWriter<W>) -> Result<()> {
+        writer.write_bits((self.hlit - 257) as u64, 5)?;
+        writer.write_bits((self.hdist - 1) as u64, 5)?;
+        writer.write_bits((self.hclen - 4) as u64, 4)?;
+        for sym in CL_ORDER.iter().take(self.hclen) {
+            let len = self.code_lengths.get_code(*sym as u32).map(|code| code.len).unwrap_or(0);
+            writer.write_bits(len as u64, 3)?;
+        }
+        for (sym, extra_bits, extra) in self.lengths.iter() {
+            self.code_lengths.write_code(writer, *sym)?;
+            writer.write_bits(*extra as u64, *extra_bits)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_tokens<W: Write>(writer: &mut BitWriter<W>, tokens: &[Token], lit_len: &Huffman, distances: &Huffman) -> Result<()> {
+    for token in tokens.iter() {
+        match *token {
+            Token::Literal(b) => lit_len.write_code(writer, b as u32)?,
+            Token::Match { len, dist } => {
+                let (code, extra_bits, extra) = length_code(len);
+                lit_len.write_code(writer, 257 + code)?;
+                writer.write_bits(extra as u64, extra_bits)?;
+                let (code, extra_bits, extra) = bucket(dist as u32 - 1);
+                distances.write_code(writer, code)?;
+                writer.write_bits(extra as u64, extra_bits)?;
+            },
+        }
+    }
+    lit_len.write_code(writer, END_OF_BLOCK)
+}
+
+/// writes `raw`, parsed into `tokens`, as the stored, fixed or dynamic block that is smallest
+fn write_block<W: Write>(writer: &mut BitWriter<W>, tokens: &[Token], raw: &[u8], last: bool, fixed: &(Huffman, Huffman)) -> Result<()> {
+    let dynamic = Dynamic::new(tokens)?;
+    let dynamic_bits = dynamic.header_bits() + data_bits(tokens, &dynamic.lit_len, &dynamic.distances).unwrap_or(u64::MAX / 4);
+    let fixed_bits = data_bits(tokens, &fixed.0, &fixed.1).unwrap_or(u64::MAX / 4);
+    //every piece takes the block header, up to 7 bits of padding and 32 bits of length
+    let stored_bits = raw.len().div_ceil(MAX_STORED).max(1) as u64 * 42 + raw.len() as u64 * 8;
+    if stored_bits < dynamic_bits.min(fixed_bits) {
+        let mut pieces = raw.chunks(MAX_STORED).peekable();
+        if pieces.peek().is_none() {
+            return write_stored(writer, &[], last);
+        }
+        while let Some(piece) = pieces.next() {
+            write_stored(writer, piece, last && pieces.peek().is_none())?;
+        }
+        return Ok(());
+    }
+    writer.write_bits(last as u64, 1)?;
+    if fixed_bits <= dynamic_bits {
+        writer.write_bits(FIXED, 2)?;
+        write_tokens(writer, tokens, &fixed.0, &fixed.1)
+    } else {
+        writer.write_bits(DYNAMIC, 2)?;
+        dynamic.write_header(writer)?;
+        write_tokens(writer, tokens, &dynamic.lit_len, &dynamic.distances)
+    }
+}
+
+fn write_stored<W: Write>(writer: &mut BitWriter<W>, piece: &[u8], last: bool) -> Result<()> {
+    writer.write_bits(last as u64, 1)?;
+    writer.write_bits(STORED, 2)?;
+    writer.align()?;
+    writer.write_bits(piece.len() as u64, 16)?;
+    writer.write_bits(!piece.len() as u64 & 0xffff, 16)?;
+    for b in piece.iter() {
+        writer.write_byte(*b)?;
+    }
+    Ok(())
+}
+
+/// Compresses `input` into a raw DEFLATE stream (RFC 1951). The input is matched in blocks of
+/// `CHUNK` bytes with the 32 KiB before them as window, every block is written as stored,
+/// fixed or dynamic huffman block, whichever is smallest.
+/// Returns the checksums and size of the input.
+pub fn deflate<R: Read, W: Write>(input: &mut R, writer: &mut BitWriter<W>, settings: Settings) -> Result<Sums> {
+    if settings.effort == 0 {
+        return Err(Error::Compress("the effort has to be at least 1".to_string(), String::new()));
+    }
+    let settings = Settings { window: settings.window.clamp(1, WINDOW), ..settings };
+    let fixed = fixed_tables()?;
+    let mut sums = Sums::new();
+    let mut data: Vec<u8> = Vec::with_capacity(WINDOW + CHUNK);
+    loop {
+        if data.len() > WINDOW {
+            data.drain(..data.len() - WINDOW);
+        }
+        let start = data.len();
+        let got = input.take(CHUNK as u64).read_to_end(&mut data).map_err(|err| Error::Compress("could not read input".to_string(), err.to_string()))?;
+        sums.update(&data[start..]);
+        let last = got < CHUNK;
+        let tokens = lz77::parse(&data, start, settings);
+        write_block(writer, &tokens, &data[start..], last, &fixed)?;
+        if last {
+            return Ok(sums);
+        }
+    }
+}
+
+/// the decoded bytes with the window the next matches may reach into
+struct Inflater<'w, W: Write> {
+    output: &'w mut W,
+    data: Vec<u8>,
+    sums: Sums,
+}
+
+impl<'w, W: Write> Inflater<'w, W> {
+    /// hands all but the last `WINDOW` bytes to the output, all of them at the end
+    fn flush(&mut self, keep: usize) -> Result<()> {
+        let done = self.data.len().saturating_sub(keep);
+        self.output.write_all(&self.data[..done]).map_err(|err| Error::Finalizing("could not write output".to_string(), err.to_string()))?;
+        self.sums.update(&self.data[..done]);
+        self.data.drain(..done);
+        Ok(())
+    }
+
+    fn block<R: Read>(&mut self, reader: &mut BitReader<R>, lit_len: &DecodeTable, distances: &DecodeTable) -> Result<()> {
+        loop {
+            let sym = lit_len.decode(reader)?;
+            if sym < END_OF_BLOCK {
+                self.data.push(sym as u8);
+            } else if sym == END_OF_BLOCK {
+                return Ok(());
+            } else {
+                let code = (sym - 257) as usize;
+                if code >= LENGTH_BASE.len() {
+                    return Err(Error::DeCompress("invalid length code".to_string(), format!("{sym}")));
+                }
+                let len = LENGTH_BASE[code] as usize + reader.read_bits(LENGTH_EXTRA[code] as u32)? as usize;
+                let code = distances.decode(reader)?;
+                if code >= 30 {
+                    return Err(Error::DeCompress("invalid distance code".to_string(), format!("{code}")));
+                }
+                let (base, extra_bits) = bucket_base(code);
+                let dist = (base + reader.read_bits(extra_bits)?) as usize + 1;
+                if dist > self.data.len() {
+                    return Err(Error::DeCompress("distance reaches before the start of the data".to_string(), format!("{dist}")));
+                }
+                let start = self.data.len() - dist;
+                for idx in start..start + len {
+                    self.data.push(self.data[idx]);
+                }
+            }
+            if self.data.len() >= FLUSH + WINDOW {
+                self.flush(WINDOW)?;
+            }
+        }
+    }
+
+    fn stored<R: Read>(&mut self, reader: &mut BitReader<R>) -> Result<()> {
+        reader.align()?;
+        let len = reader.read_bits(16)?;
+        let nlen = reader.read_bits(16)?;
+        if len != !nlen & 0xffff {
+            return Err(Error::DeCompress("stored block length does not match its complement".to_string(), format!("{len} {nlen}")));
+        }
+        for _ in 0..len {
+            self.data.push(reader.read_byte()?);
+        }
+        Ok(())
+    }
+}
+
+/// reads the code lengths of a dynamic block and builds its two tables
+fn read_dynamic<R: Read>(reader: &mut BitReader<R>) -> Result<(DecodeTable, DecodeTable)> {
+    let hlit = reader.read_bits(5)? as usize + 257;
+    let hdist = reader.read_bits(5)? as usize + 1;
+    let hclen = reader.read_bits(4)? as usize + 4;
+    let mut cl_lengths = Vec::new();
+    for sym in CL_ORDER.iter().take(hclen) {
+        let len = reader.read_bits(3)? as u8;
+        if len > 0 {
+            cl_lengths.push((*sym as u32, len));
+        }
+    }
+    let code_lengths = Huffman::from_code_lengths(Mode::Bytes, cl_lengths)?.decode_table();
+    let mut lengths: Vec<u8> = Vec::with_capacity(hlit + hdist);
+    while lengths.len() < hlit + hdist {
+        let (len, repeat) = match code_lengths.decode(reader)? {
+            sym @ 0..=15 => (sym as u8, 1),
+            16 => match lengths.last() {
+                Some(prev) => (*prev, 3 + reader.read_bits(2)? as usize),
+                None => return Err(Error::DeCompress("code length repeat without a previous length".to_string(), String::new())),
+            },
+            17 => (0, 3 + reader.read_bits(3)? as usize),
+            _ => (0, 11 + reader.read_bits(7)? as usize),
+        };
+        if lengths.len() + repeat > hlit + hdist {
+            return Err(Error::DeCompress("code lengths overflow the table".to_string(), String::new()));
+        }
+        lengths.extend(std::iter::repeat_n(len, repeat));
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(Error::DeCompress("block has no end of block code".to_string(), String::new()));
+    }
+    let table = |lengths: &[u8]| -> Result<DecodeTable> {
+        let pairs = lengths.iter().enumerate().filter(|(_, len)| **len > 0).map(|(sym, len)| (sym as u32, *len)).collect();
+        Ok(Huffman::from_code_lengths(Mode::Bytes, pairs)?.decode_table())
+    };
+    Ok((table(&lengths[..hlit])?, table(&lengths[hlit..])?))
+}
+
+/// Decompresses a raw DEFLATE stream (RFC 1951) up to its final block, so whatever follows
+/// it can still be read from `reader`. Returns the checksums and size of the output.
+pub fn inflate<R: Read, W: Write>(reader: &mut BitReader<R>, output: &mut W) -> Result<Sums> {
+    let fixed = fixed_tables()?;
+    let fixed = (fixed.0.decode_table(), fixed.1.decode_table());
+    let mut inflater = Inflater { output, data: Vec::new(), sums: Sums::new() };
+    loop {
+        let last = reader.read_bits(1)? == 1;
+        match reader.read_bits(2)? {
+            STORED => inflater.stored(reader)?,
+            FIXED => inflater.block(reader, &fixed.0, &fixed.1)?,
+            DYNAMIC => {
+                let (lit_len, distances) = read_dynamic(reader)?;
+                inflater.block(reader, &lit_len, &distances)?;
+            },
+            kind => return Err(Error::DeCompress("reserved block type".to_string(), format!("{kind}"))),
+        }
+        if last {
+            break;
+        }
+        if inflater.data.len() >= FLUSH + WINDOW {
+            inflater.flush(WINDOW)?;
+        }
+    }
+    inflater.flush(0)?;
+    inflater.output.flush().map_err(|err| Error::Finalizing("could not flush output".to_string(), err.to_string()))?;
+    Ok(inflater.sums)
+}
+
+/// Compresses `input` into a gzip member (RFC 1952) carrying `name` and `mtime`.
+/// Returns the number of bytes read.
+pub fn gzip<R: Read, W: Write>(input: &mut R, output: &mut W, name: Option<&str>, mtime: u32, settings: Settings) -> Result<u64> {
+    //the name is zero terminated, so it can not hold a zero itself
+    let name = name.filter(|name| !name.contains('\0'));
+    let mut header = GZIP_MAGIC.to_vec();
+    header.push(if name.is_some() { GZIP_NAME } else { 0 });
+    header.extend_from_slice(&mtime.to_le_bytes());
+    //no extra flags, unknown operating system
+    header.extend_from_slice(&[0, 255]);
+    if let Some(name) = name {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+    }
+    output.write_all(&header).map_err(|err| Error::Compress("could not write output".to_string(), err.to_string()))?;
+    let mut writer = BitWriter::new(output, BitOrder::Lsb);
+    let sums = deflate(input, &mut writer, settings)?;
+    let output = writer.finish()?;
+    output.write_all(&sums.crc.finish().to_le_bytes())
+        .and_then(|_| output.write_all(&(sums.size as u32).to_le_bytes()))
+        .and_then(|_| output.flush())
+        .map_err(|err| Error::Compress("could not write output".to_string(), err.to_string()))?;
+    Ok(sums.size)
+}
+
+fn read_le<R: Read>(reader: &mut BitReader<R>, bytes: u32) -> Result<u64> {
+    reader.read_bits(8 * bytes)
+}
+
+/// Decompresses all gzip members of `input` one after the other.
+/// Returns the number of bytes written and the file name stored in the first member.
+pub fn gunzip<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<(u64, Option<String>)> {
+    let mut reader = BitReader::new(input, BitOrder::Lsb);
+    let mut name = None;
+    let mut total = 0;
+    loop {
+        let mut header = Vec::new();
+        for _ in 0..10 {
+            header.push(reader.read_byte()?);
+        }
+        if !is_gzip(&header) {
+            return Err(Error::DeCompress("not a gzip member".to_string(), format!("{:02x?}", &header[..3])));
+        }
+        let flags = header[3];
+        if flags & 0xe0 != 0 {
+            return Err(Error::DeCompress("unknown gzip flags".to_string(), format!("{flags:#x}")));
+        }
+        if flags & GZIP_EXTRA != 0 {
+            let len = read_le(&mut reader, 2)?;
+            header.extend_from_slice(&(len as u16).to_le_bytes());
+            for _ in 0..len {
+                header.push(reader.read_byte()?);
+            }
+        }
+        for field in [GZIP_NAME, GZIP_COMMENT] {
+            if flags & field == 0 {
+                continue;
+            }
+            let mut text = Vec::new();
+            loop {
+                match reader.read_byte()? {
+                    0 => break,
+                    b => text.push(b),
+                }
+            }
+            header.extend_from_slice(&text);
+            header.push(0);
+            if field == GZIP_NAME && name.is_none() {
+                //iso 8859-1 by the rfc, every byte is its own char
+                name = Some(String::from_utf8(text.clone()).unwrap_or_else(|_| text.iter().map(|b| *b as char).collect()));
+            }
+        }
+        if flags & GZIP_HCRC != 0 && read_le(&mut reader, 2)? != crc32(&header) as u64 & 0xffff {
+            return Err(Error::Integrity("gzip header checksum does not match".to_string(), String::new()));
+        }
+        let sums = inflate(&mut reader, output)?;
+        reader.align()?;
+        let (crc, size) = (read_le(&mut reader, 4)?, read_le(&mut reader, 4)?);
+        if crc != sums.crc.finish() as u64 || size != sums.size & 0xffff_ffff {
+            return Err(Error::Integrity("gzip checksum or size does not match the data".to_string(), format!("crc {:08x}, size {}", crc, size)));
+        }
+        total += sums.size;
+        if reader.at_end()? {
+            return Ok((total, name));
+        }
+    }
+}
+
+/// Compresses `input` into a zlib stream (RFC 1950). Returns the number of bytes read.
+pub fn zlib<R: Read, W: Write>(input: &mut R, output: &mut W, settings: Settings) -> Result<u64> {
+    output.write_all(&ZLIB_HEADER).map_err(|err| Error::Compress("could not write output".to_string(), err.to_string()))?;
+    let mut writer = BitWriter::new(output, BitOrder::Lsb);
+    let sums = deflate(input, &mut writer, settings)?;
+    let output = writer.finish()?;
+    output.write_all(&sums.adler.finish().to_be_bytes())
+        .and_then(|_| output.flush())
+        .map_err(|err| Error::Compress("could not write output".to_string(), err.to_string()))?;
+    Ok(sums.size)
+}
+
+/// Decompresses a zlib stream (RFC 1950), preset dictionaries are not supported.
+/// Returns the number of bytes written.
+pub fn unzlib<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<u64> {
+    let mut reader = BitReader::new(input, BitOrder::Lsb);
+    let (cmf, flags) = (reader.read_byte()?, reader.read_byte()?);
+    if cmf & 0x0f != 8 || cmf >> 4 > 7 || !(cmf as u16 * 256 + flags as u16).is_multiple_of(31) {
+        return Err(Error::DeCompress("not a zlib stream".to_string(), format!("{:02x} {:02x}", cmf, flags)));
+    }
+    if flags & ZLIB_DICT != 0 {
+        return Err(Error::DeCompress("zlib preset dictionaries are not supported".to_string(), String::new()));
+    }
+    let sums = inflate(&mut reader, output)?;
+    reader.align()?;
+    let mut adler = [0u8; 4];
+    for b in adler.iter_mut() {
+        *b = reader.read_byte()?;
+    }
+    if u32::from_be_bytes(adler) != sums.adler.finish() {
+        return Err(Error::Integrity("zlib checksum does not match the data".to_string(), String::new()));
+    }
+    Ok(sums.size)
+}
+
+/// Compresses `input` into a raw DEFLATE stream. Returns the number of bytes read.
+pub fn compress_raw<R: Read, W: Write>(input: &mut R, output: &mut W, settings: Settings) -> Result<u64> {
+    let mut writer = BitWriter::new(output, BitOrder::Lsb);
+    let sums = deflate(input, &mut writer, settings)?;
+    writer.finish()?;
+    Ok(sums.size)
+}
+
+/// Decompresses a raw DEFLATE stream. Returns the number of bytes written.
+pub fn decompress_raw<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<u64> {
+    let mut reader = BitReader::new(input, BitOrder::Lsb);
+    Ok(inflate(&mut reader, output)?.size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// `echo 'hello hello hello hello' | gzip -9 -n`
+    const HELLO_GZIP: [u8; 41] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48,
+        0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x00, 0x88, 0x59,
+        0x0b, 0x18, 0x00, 0x00, 0x00, 0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x02, 0x03, 0x00, 0x00,
+    ];
+    /// `zlib.compress(b'hello hello hello hello\n', 9)`
+    const HELLO_ZLIB: [u8; 17] = [0x78, 0xda, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x70, 0xbe, 0x08, 0xbb];
+
+    #[test]
+    fn reads_system_gzip() {
+        let mut out = Vec::new();
+        assert_eq!(gunzip(&mut &HELLO_GZIP[..29], &mut out).unwrap(), (24, None));
+        assert_eq!(out, b"hello hello hello hello\n");
+        //a second member that is cut off
+        assert!(gunzip(&mut &HELLO_GZIP[..], &mut Vec::new()).is_err());
+        out.clear();
+        assert_eq!(unzlib(&mut &HELLO_ZLIB[..], &mut out).unwrap(), 24);
+        assert_eq!(out, b"hello hello hello hello\n");
+
+        //dynamic blocks, written by `gzip -9 -n`
+        let compressed = std::fs::read("wizard_of_oz.txt.gz").unwrap();
+        out.clear();
+        gunzip(&mut compressed.as_slice(), &mut out).unwrap();
+        assert_eq!(out, std::fs::read("wizard_of_oz.txt").unwrap());
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let random: Vec<u8> = (0..100_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        for data in [&text[..], &random[..], b"", b"a", &[7u8; 70_000][..]] {
+            let mut compressed = Vec::new();
+            gzip(&mut &data[..], &mut compressed, Some("data.bin"), 1_700_000_000, Settings::default()).unwrap();
+            let mut out = Vec::new();
+            assert_eq!(gunzip(&mut compressed.as_slice(), &mut out).unwrap(), (data.len() as u64, Some("data.bin".to_string())));
+            assert_eq!(out, data);
+            //never much larger than the input
+            assert!(compressed.len() < data.len() + data.len() / 1000 + 64);
+
+            let mut compressed = Vec::new();
+            zlib(&mut &data[..], &mut compressed, Settings::default()).unwrap();
+            assert_eq!(compressed[..2], ZLIB_HEADER);
+            out.clear();
+            unzlib(&mut compressed.as_slice(), &mut out).unwrap();
+            assert_eq!(out, data);
+        }
+        let mut compressed = Vec::new();
+        compress_raw(&mut &text[..], &mut compressed, Settings::default()).unwrap();
+        assert!(compressed.len() < text.len() * 4 / 10);
+        let mut out = Vec::new();
+        decompress_raw(&mut compressed.as_slice(), &mut out).unwrap();
+        assert_eq!(out, text);
+    }
+
+    #[test]
+    fn code_length_runs() {
+        let lengths = [[3u8; 9].to_vec(), vec![0; 150], vec![5, 5, 0, 0, 0, 0, 2]].concat();
+        let codes = run_lengths(&lengths);
+        let mut back = Vec::new();
+        for (sym, _, extra) in codes {
+            match sym {
+                16 => back.extend(std::iter::repeat_n(*back.last().unwrap(), 3 + extra as usize)),
+                17 => back.extend(std::iter::repeat_n(0, 3 + extra as usize)),
+                18 => back.extend(std::iter::repeat_n(0, 11 + extra as usize)),
+                len => back.push(len as u8),
+            }
+        }
+        assert_eq!(back, lengths);
+        assert_eq!(length_code(3), (0, 0, 0));
+        assert_eq!(length_code(257), (27, 5, 30));
+        assert_eq!(length_code(258), (28, 0, 0));
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut compressed = Vec::new();
+        gzip(&mut &b"some text to check"[..], &mut compressed, None, 0, Settings::default()).unwrap();
+        let last = compressed.len() - 5;
+        compressed[last] ^= 1;
+        match gunzip(&mut compressed.as_slice(), &mut Vec::new()) {
+            Err(Error::Integrity(_, _)) => {},
+            other => panic!("expected an integrity error, got {:?}", other),
+        }
+        assert!(unzlib(&mut &[0x78, 0x9d, 0x03, 0x00][..], &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use crate::error::{Result, Error};
+use crate::huffman::{Huffman, Mode};
+use crate::bits::{BitOrder, BitReader, BitWriter};
+use crate::crc::crc32;
+use crate::table::DecodeTable;
+use std::fmt;
+use std::io::Read;
+
+/// first bytes of every dictionary file
+pub const DICT_MAGIC: [u8; 4] = [0x89, b'H', b'U', b'D'];
+const DICT_VERSION: u8 = 1;
+
+/// A code table trained on a corpus, so similar inputs can be coded without storing a table.
+/// Symbols the corpus did not have are written as the escape code followed by the plain symbol.
+pub struct Dictionary {
+    /// the CRC-32 of the table, compressed files refer to the dictionary by it
+    pub id: u32,
+    pub mode: Mode,
+    max_len: u8,
+    huffman: Huffman,
+    table: DecodeTable,
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Dictionary {{ id: {:08x}, mode: {:?} }}", self.id, self.mode)
+    }
+}
+
+/// the symbol that announces a plain symbol, one that never occurs in the mode or hardly ever
+fn escape(mode: Mode) -> u32 {
+    match mode {
+        Mode::Bytes => 256,
+        Mode::Text => 0xffff,
+    }
+}
+
+/// bits of a plain symbol after the escape code
+fn plain_bits(mode: Mode) -> u32 {
+    match mode {
+        Mode::Bytes => 8,
+        Mode::Text => 21,
+    }
+}
+
+/// the symbols of `data`, text has to be valid utf-8
+fn symbols(mode: Mode, data: &[u8]) -> Result<Vec<u32>> {
+    if mode == Mode::Text {
+        std::str::from_utf8(data).map_err(|err| Error::Compress("the dictionary is for utf-8 text".to_string(), err.to_string()))?;
+    }
+    Ok(mode.symbols(data)?.collect())
+}
+
+/// the dictionary file of `huffman`, see `Dictionary::to_bytes`
+fn file(mode: Mode, max_len: u8, huffman: &Huffman) -> Result<Vec<u8>> {
+    let mut writer = BitWriter::new(DICT_MAGIC.to_vec(), BitOrder::Lsb);
+    writer.write_byte(DICT_VERSION)?;
+    writer.write_byte(mode.to_byte())?;
+    writer.write_byte(max_len)?;
+    huffman.write_lengths(&mut writer)?;
+    let mut bytes = writer.finish()?;
+    let crc = crc32(&bytes);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+    Ok(bytes)
+}
+
+impl Dictionary {
+    /// Builds the code table of all symbols in `samples`, codes are at most `max_len` bits (0 for no limit).
+    /// The escape symbol is counted once, so it gets a code however large the corpus is.
+    pub fn train(mode: Mode, samples: &[Vec<u8>], max_len: u8) -> Result<Self> {
+        let mut all = vec![escape(mode)];
+        for sample in samples.iter() {
+            all.extend(symbols(mode, sample)?);
+        }
+        let huffman = Huffman::with_symbols(mode, &all, max_len)?;
+        //what is used is always what a reader of the file gets
+        Self::from_bytes(&file(mode, max_len, &huffman)?)
+    }
+
+    /// The dictionary file: 4 bytes magic, 1 byte version, 1 byte symbol mode, 1 byte code length
+    /// limit, the code lengths (see `Huffman::write_lengths`) and the CRC-32 of all of these as
+    /// 4 bytes big endian, which is also the id of the dictionary.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        file(self.mode, self.max_len, &self.huffman)
+    }
+
+    /// reads and validates a dictionary written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < DICT_MAGIC.len() + 7 || bytes[..DICT_MAGIC.len()] != DICT_MAGIC {
+            return Err(Error::DeCompress("not a dictionary file".to_string(), String::new()));
+        }
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        let id = u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]);
+        if crc32(body) != id {
+            return Err(Error::Integrity("dictionary checksum mismatch".to_string(), format!("stored {:#010x}, computed {:#010x}", id, crc32(body))));
+        }
+        let mut reader = BitReader::new(&body[DICT_MAGIC.len()..], BitOrder::Lsb);
+        let version = reader.read_byte()?;
+        if version != DICT_VERSION {
+            return Err(Error::DeCompress(format!("unsupported dictionary version, this build reads version {}", DICT_VERSION), format!("{version}")));
+        }
+        let mode = Mode::from_byte(reader.read_byte()?)?;
+        let max_len = reader.read_byte()?;
+        let huffman = Huffman::from_lengths(&mut reader, mode, max_len)?;
+        if huffman.get_code(escape(mode)).is_none() {
+            return Err(Error::DeCompress("dictionary has no escape code".to_string(), String::new()));
+        }
+        let table = huffman.decode_table();
+        Ok(Self { id, mode, max_len, huffman, table })
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> Result<Self> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes).map_err(|err| Error::DeCompress("could not read dictionary".to_string(), err.to_string()))?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Codes `data` with the trained codes: the number of symbols as a varint (7 bits per byte,
+    /// the high bit set on all but the last), then the codes. A symbol without a code, and the
+    /// escape symbol itself, is written as the escape code and the symbol in 8 bits (21 for text).
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let symbols = symbols(self.mode, data)?;
+        let mut count = Vec::new();
+        let mut left = symbols.len() as u64;
+        while left >= 0x80 {
+            count.push(left as u8 | 0x80);
+            left >>= 7;
+        }
+        count.push(left as u8);
+        let mut writer = BitWriter::new(count, BitOrder::Lsb);
+        let escape = escape(self.mode);
+        for sym in symbols {
+            match self.huffman.get_code(sym).filter(|_| sym != escape) {
+                Some(code) => writer.write_code(code.bits, code.len as u32)?,
+                None => {
+                    self.huffman.write_code(&mut writer, escape)?;
+                    writer.write_bits(sym as u64, plain_bits(self.mode))?;
+                },
+            }
+        }
+        writer.finish()
+    }
+
+    /// decodes what `encode` wrote
+    pub fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = BitReader::new(bytes, BitOrder::Lsb);
+        let mut count = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = reader.read_byte()?;
+            count |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+        //every code takes at least one bit
+        if count > (bytes.len() as u64 * 8).saturating_sub(reader.bits_read()) {
+            return Err(Error::DeCompress("symbol count exceeds the coded data".to_string(), format!("{count}")));
+        }
+        let escape = escape(self.mode);
+        let mut data = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let mut sym = self.table.decode(&mut reader)?;
+            if sym == escape {
+                sym = reader.read_bits(plain_bits(self.mode))? as u32;
+                if self.mode == Mode::Text && char::from_u32(sym).is_none() {
+                    return Err(Error::DeCompress("escaped symbol is not a char".to_string(), format!("{sym:#x}")));
+                }
+            }
+            self.mode.push_symbol(sym, &mut data);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// the lines of the book as separate short messages
+    fn messages() -> Vec<Vec<u8>> {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        text.split(|b| *b == b'\n').filter(|line| line.len() > 20).map(|line| line.to_vec()).collect()
+    }
+
+    #[test]
+    fn short_messages() {
+        let messages = messages();
+        let (corpus, rest) = messages.split_at(messages.len() / 2);
+        let dictionary = Dictionary::train(Mode::Bytes, corpus, 0).unwrap();
+        let (mut plain, mut coded) = (0, 0);
+        for message in rest.iter().take(200) {
+            let encoded = dictionary.encode(message).unwrap();
+            assert_eq!(dictionary.decode(&encoded).unwrap(), *message);
+            plain += message.len();
+            coded += encoded.len();
+        }
+        assert!(coded * 100 < plain * 62, "{} of {} bytes", coded, plain);
+
+        //bytes the corpus did not have are escaped
+        for data in [&b"\x00\xff{|}~ zebra"[..], b"", &[0x80; 300]] {
+            assert_eq!(dictionary.decode(&dictionary.encode(data).unwrap()).unwrap(), data);
+        }
+        let encoded = dictionary.encode(b"a message that gets cut off").unwrap();
+        assert!(dictionary.decode(&encoded[..encoded.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn text_and_files() {
+        let samples = vec!["grüße aus köln".as_bytes().to_vec(), "grüß gott".as_bytes().to_vec()];
+        let dictionary = Dictionary::train(Mode::Text, &samples, 12).unwrap();
+        for data in ["köln grüßt", "日本 and \u{ffff}", ""] {
+            assert_eq!(dictionary.decode(&dictionary.encode(data.as_bytes()).unwrap()).unwrap(), data.as_bytes());
+        }
+        assert!(dictionary.encode(&[0xff, 0xfe]).is_err());
+
+        let bytes = dictionary.to_bytes().unwrap();
+        let read = Dictionary::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!((read.id, read.mode), (dictionary.id, Mode::Text));
+        //the same corpus always gives the same dictionary
+        assert_eq!(Dictionary::train(Mode::Text, &samples, 12).unwrap().id, dictionary.id);
+        let mut corrupt = bytes.clone();
+        corrupt[8] ^= 1;
+        assert!(Dictionary::from_bytes(&corrupt).is_err());
+        assert!(Dictionary::from_bytes(b"not a dictionary").is_err());
+    }
+}
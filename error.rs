@@ -10,7 +10,8 @@ pub enum Error {
     Encoding(String, String),
     Compress(String, String),
     DeCompress(String, String),
-    Finalizing(String, String)
+    Finalizing(String, String),
+    Integrity(String, String)
 }
 
 impl fmt::Display for Error {
@@ -23,6 +24,7 @@ impl fmt::Display for Error {
             Error::Compress(msg, err) => f.write_fmt(format_args!("[ERROR] - compress: {}; mapped: {};", msg, err)),
             Error::DeCompress(msg, err) => f.write_fmt(format_args!("[ERROR] - decompress: {}; mapped: {};", msg, err)),
             Error::Finalizing(msg, err) => f.write_fmt(format_args!("[ERROR] - finalizing: {}; mapped: {};", msg, err)),
+            Error::Integrity(msg, err) => f.write_fmt(format_args!("[ERROR] - integrity: {}; mapped: {};", msg, err)),
         }
     }
 }
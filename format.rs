@@ -0,0 +1,417 @@
+use crate::error::{Result, Error};
+use crate::huffman::Mode;
+use crate::crc::{crc32, CrcReader, CrcWriter};
+use crate::huffman::Decoder;
+use crate::stream::{self, Options};
+use crate::adaptive;
+use crate::coder::Coder;
+use crate::dictionary::Dictionary;
+use crate::seek::Index;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+/// first bytes of every compressed file, the non-ascii lead keeps text files from matching
+pub const MAGIC: [u8; 4] = [0x89, b'H', b'U', b'F'];
+/// newest format version this build writes and reads
+pub const VERSION: u8 = 2;
+
+/// the body is one adaptive huffman stream instead of blocks with tables
+pub const FLAG_ADAPTIVE: u8 = 0x01;
+/// the original size follows the fixed part of the header
+pub const FLAG_SIZE: u8 = 0x02;
+/// the name of the original file follows the size
+pub const FLAG_NAME: u8 = 0x04;
+/// the permissions and modification time of the original file follow the name
+pub const FLAG_META: u8 = 0x08;
+/// the entropy coder follows the file attributes, huffman codes if it is not set
+pub const FLAG_CODER: u8 = 0x10;
+/// the id of the dictionary the blocks may be coded with follows the entropy coder
+pub const FLAG_DICT: u8 = 0x20;
+/// every block carries its own table and a block index follows the checksum of the data
+pub const FLAG_INDEX: u8 = 0x40;
+/// every flag this version understands, files with other flags are rejected
+const KNOWN_FLAGS: u8 = FLAG_ADAPTIVE | FLAG_SIZE | FLAG_NAME | FLAG_META | FLAG_CODER | FLAG_DICT | FLAG_INDEX;
+
+/// file attributes restored on decompression
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Meta {
+    /// unix permission bits
+    pub permissions: u32,
+    /// seconds since the unix epoch
+    pub mtime: u64,
+}
+
+/// The header in front of every compressed file:
+/// 4 bytes magic, 1 byte version, 1 byte flags, 1 byte symbol mode,
+/// with `FLAG_SIZE` the original size as 8 bytes big endian,
+/// with `FLAG_NAME` the file name as 2 bytes length and utf-8 bytes,
+/// with `FLAG_META` the permissions as 4 bytes and the mtime as 8 bytes big endian,
+/// with `FLAG_CODER` the entropy coder as 1 byte (see `Coder::to_byte`),
+/// with `FLAG_DICT` the id of the dictionary as 4 bytes big endian,
+/// and the CRC-32 of all of these as 4 bytes big endian. The body is followed by the CRC-32 of the original data (see `write_checksum`),
+/// with `FLAG_INDEX` by the block index after it (see `seek::Index`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub flags: u8,
+    pub mode: Mode,
+    pub size: Option<u64>,
+    pub name: Option<String>,
+    pub meta: Option<Meta>,
+    pub coder: Coder,
+    pub dictionary: Option<u32>,
+}
+
+impl Header {
+    /// a header of the current version, `size` is left out when it is unknown (like for stdin)
+    pub fn new(mode: Mode, size: Option<u64>) -> Self {
+        Self {
+            version: VERSION,
+            flags: if size.is_some() { FLAG_SIZE } else { 0 },
+            mode,
+            size,
+            name: None,
+            meta: None,
+            coder: Coder::Huffman,
+            dictionary: None,
+        }
+    }
+
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.flags |= FLAG_SIZE;
+        self.size = Some(size);
+        self
+    }
+
+    /// stores the name of the original file, only its last component is kept
+    pub fn with_name(mut self, name: &str) -> Self {
+        let name = file_name(name);
+        if !name.is_empty() && name.len() <= u16::MAX as usize {
+            self.flags |= FLAG_NAME;
+            self.name = Some(name.to_string());
+        }
+        self
+    }
+
+    pub fn with_meta(mut self, meta: Meta) -> Self {
+        self.flags |= FLAG_META;
+        self.meta = Some(meta);
+        self
+    }
+
+    /// records the entropy coder of the blocks, huffman codes need no flag
+    pub fn with_coder(mut self, coder: Coder) -> Self {
+        self.flags &= !FLAG_CODER;
+        if coder != Coder::Huffman {
+            self.flags |= FLAG_CODER;
+        }
+        self.coder = coder;
+        self
+    }
+
+    /// records the id of the dictionary the blocks may be coded with
+    pub fn with_dictionary(mut self, id: u32) -> Self {
+        self.flags |= FLAG_DICT;
+        self.dictionary = Some(id);
+        self
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag == flag
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> Result<()> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(self.version);
+        bytes.push(self.flags);
+        bytes.push(self.mode.to_byte());
+        if let Some(size) = self.size {
+            bytes.extend_from_slice(&size.to_be_bytes());
+        }
+        if let Some(name) = self.name.as_ref() {
+            bytes.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+        }
+        if let Some(meta) = self.meta {
+            bytes.extend_from_slice(&meta.permissions.to_be_bytes());
+            bytes.extend_from_slice(&meta.mtime.to_be_bytes());
+        }
+        if self.has(FLAG_CODER) {
+            bytes.push(self.coder.to_byte());
+        }
+        if let Some(id) = self.dictionary {
+            bytes.extend_from_slice(&id.to_be_bytes());
+        }
+        let crc = crc32(&bytes);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        output.write_all(&bytes).map_err(|err| Error::Compress("could not write header".to_string(), err.to_string()))
+    }
+
+    /// reads and validates a header, fails for anything that is not a compressed file
+    /// of a version and with features this build supports
+    pub fn read<R: Read>(input: &mut R) -> Result<Self> {
+        let mut fixed = [0u8; 7];
+        input.read_exact(&mut fixed).map_err(|err| Error::DeCompress("input is too short for a header".to_string(), err.to_string()))?;
+        if !is_compressed(&fixed) {
+            return Err(Error::DeCompress("input is not a huf file".to_string(), format!("starts with {:02x?}", &fixed[..4])));
+        }
+        let (version, flags) = (fixed[4], fixed[5]);
+        if version != VERSION {
+            return Err(Error::DeCompress(format!("unsupported format version, this build reads version {}", VERSION), format!("{version}")));
+        }
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::DeCompress("unsupported feature flags".to_string(), format!("{:#04x}", flags & !KNOWN_FLAGS)));
+        }
+        let mut bytes = fixed.to_vec();
+        let size = match flags & FLAG_SIZE {
+            0 => None,
+            _ => Some(u64::from_be_bytes(read_field(input, &mut bytes, 8, "original size")?.try_into().unwrap_or_default())),
+        };
+        let name = match flags & FLAG_NAME {
+            0 => None,
+            _ => {
+                let len = read_field(input, &mut bytes, 2, "name length")?;
+                let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                let name = read_field(input, &mut bytes, len, "name")?;
+                let name = String::from_utf8(name).map_err(|err| Error::DeCompress("stored name is not utf-8".to_string(), err.to_string()))?;
+                Some(name)
+            },
+        };
+        let meta = match flags & FLAG_META {
+            0 => None,
+            _ => {
+                let field = read_field(input, &mut bytes, 12, "file attributes")?;
+                Some(Meta {
+                    permissions: u32::from_be_bytes([field[0], field[1], field[2], field[3]]),
+                    mtime: u64::from_be_bytes(field[4..].try_into().unwrap_or_default()),
+                })
+            },
+        };
+        let coder = match flags & FLAG_CODER {
+            0 => None,
+            _ => Some(read_field(input, &mut bytes, 1, "entropy coder")?[0]),
+        };
+        let dictionary = match flags & FLAG_DICT {
+            0 => None,
+            _ => Some(u32::from_be_bytes(read_field(input, &mut bytes, 4, "dictionary id")?.try_into().unwrap_or_default())),
+        };
+        let mut crc = [0u8; 4];
+        input.read_exact(&mut crc).map_err(|err| Error::DeCompress("could not read header checksum".to_string(), err.to_string()))?;
+        if u32::from_be_bytes(crc) != crc32(&bytes) {
+            return Err(Error::Integrity("header checksum mismatch".to_string(), format!("stored {:#010x}, computed {:#010x}", u32::from_be_bytes(crc), crc32(&bytes))));
+        }
+        let mode = Mode::from_byte(fixed[6])?;
+        let coder = coder.map(Coder::from_byte).transpose()?.unwrap_or_default();
+        //a name is used as a path on decompression, only a plain file name is accepted
+        if let Some(name) = name.as_ref() {
+            if name.is_empty() || file_name(name) != name || name == "." || name == ".." {
+                return Err(Error::DeCompress("stored name is not a plain file name".to_string(), name.to_string()));
+            }
+        }
+        Ok(Self { version, flags, mode, size, name, meta, coder, dictionary })
+    }
+
+    /// compares the number of decompressed bytes with the recorded original size
+    pub fn check_size(&self, written: u64) -> Result<()> {
+        match self.size {
+            Some(size) if size != written => Err(Error::DeCompress("decompressed size differs from the original size".to_string(), format!("expected {} bytes, got {}", size, written))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes `header`, compresses `input` the way the header flags ask for (adaptive, or in blocks
+/// with `options`, whose entropy coder and dictionary are recorded in the header) and appends
+/// the checksum of the data. Returns the number of bytes read.
+pub fn compress<R: Read, W: Write>(input: &mut R, output: &mut W, header: &Header, options: &Options) -> Result<u64> {
+    let coder = if header.has(FLAG_ADAPTIVE) { Coder::Huffman } else { options.coder };
+    let mut header = header.clone().with_coder(coder);
+    if let Some(dictionary) = options.dictionary.as_ref().filter(|_| !header.has(FLAG_ADAPTIVE)) {
+        header = header.with_dictionary(dictionary.id);
+    }
+    if header.has(FLAG_ADAPTIVE | FLAG_INDEX) {
+        return Err(Error::Compress("a block index needs blocks, adaptive coding has none".to_string(), String::new()));
+    }
+    header.write(output)?;
+    let mut input = CrcReader::new(input);
+    let mut index = None;
+    if header.has(FLAG_ADAPTIVE) {
+        adaptive::compress(&mut input, output)?;
+    } else if header.has(FLAG_INDEX) {
+        index = Some(stream::compress_indexed(&mut input, output, options)?);
+    } else {
+        stream::compress(&mut input, output, options)?;
+    }
+    write_checksum(output, input.crc.finish())?;
+    if let Some(index) = index {
+        output.write_all(&index.to_bytes())
+            .and_then(|_| output.flush())
+            .map_err(|err| Error::Compress("could not write block index".to_string(), err.to_string()))?;
+    }
+    Ok(input.count)
+}
+
+/// Decompresses the body that follows `header`, which was already read from `input`,
+/// and verifies the original size and checksum. Returns the number of bytes written.
+/// A file compressed with a dictionary needs that same `dictionary`, blocks are decoded
+/// `threads` at a time (see `stream::decompress`).
+pub fn decompress_body<R: Read, W: Write>(input: &mut R, output: &mut W, header: &Header, decoder: Decoder, dictionary: Option<&Dictionary>, threads: usize) -> Result<u64> {
+    check_dictionary(header, dictionary)?;
+    let mut output = CrcWriter::new(output);
+    if header.has(FLAG_ADAPTIVE) {
+        adaptive::decompress(input, &mut output)?;
+    } else {
+        stream::decompress(input, &mut output, decoder, header.coder, dictionary, threads)?;
+    }
+    header.check_size(output.count)?;
+    verify_checksum(input, output.crc.finish())?;
+    if header.has(FLAG_INDEX) {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes).map_err(|err| Error::DeCompress("could not read block index".to_string(), err.to_string()))?;
+        let index = Index::from_bytes(&bytes)?;
+        if index.size != output.count {
+            return Err(Error::DeCompress("block index does not match the data".to_string(), format!("indexed {} bytes, got {}", index.size, output.count)));
+        }
+    }
+    Ok(output.count)
+}
+
+/// fails unless `dictionary` is the one the file of `header` was compressed with, if any
+pub fn check_dictionary(header: &Header, dictionary: Option<&Dictionary>) -> Result<()> {
+    match (header.dictionary, dictionary) {
+        (Some(id), None) => Err(Error::DeCompress(format!("compressed with the dictionary {:08x}, which has to be given", id), String::new())),
+        (Some(id), Some(dictionary)) if dictionary.id != id => Err(Error::DeCompress(format!("compressed with the dictionary {:08x}, not with the given one", id), format!("{:08x}", dictionary.id))),
+        _ => Ok(()),
+    }
+}
+
+/// reads `len` bytes of a header field and appends them to `bytes` for the checksum
+fn read_field<R: Read>(input: &mut R, bytes: &mut Vec<u8>, len: usize, what: &str) -> Result<Vec<u8>> {
+    let mut field = vec![0u8; len];
+    input.read_exact(&mut field).map_err(|err| Error::DeCompress(format!("could not read {}", what), err.to_string()))?;
+    bytes.extend_from_slice(&field);
+    Ok(field)
+}
+
+/// the last component of a path, split at both kinds of separators
+fn file_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or_default()
+}
+
+/// writes the CRC-32 of the original data after the body
+pub fn write_checksum<W: Write>(output: &mut W, crc: u32) -> Result<()> {
+    output.write_all(&crc.to_be_bytes())
+        .and_then(|_| output.flush())
+        .map_err(|err| Error::Compress("could not write checksum".to_string(), err.to_string()))
+}
+
+/// reads the CRC-32 stored after the body and compares it with the one of the decompressed data
+pub fn verify_checksum<R: Read>(input: &mut R, crc: u32) -> Result<()> {
+    let mut stored = [0u8; 4];
+    input.read_exact(&mut stored).map_err(|err| Error::DeCompress("could not read checksum".to_string(), err.to_string()))?;
+    let stored = u32::from_be_bytes(stored);
+    if stored != crc {
+        return Err(Error::Integrity("checksum of the decompressed data does not match the original".to_string(), format!("stored {:#010x}, computed {:#010x}", stored, crc)));
+    }
+    Ok(())
+}
+
+/// true if `prefix` starts with the magic bytes
+pub fn is_compressed(prefix: &[u8]) -> bool {
+    prefix.starts_with(&MAGIC)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for header in [Header::new(Mode::Bytes, Some(1 << 40)), Header::new(Mode::Text, None)] {
+            let mut bytes = Vec::new();
+            header.write(&mut bytes).unwrap();
+            assert!(is_compressed(&bytes));
+            assert_eq!(Header::read(&mut bytes.as_slice()).unwrap(), header);
+        }
+        let mut adaptive = Header::new(Mode::Bytes, None);
+        adaptive.flags |= FLAG_ADAPTIVE;
+        assert!(adaptive.has(FLAG_ADAPTIVE));
+        assert!(!adaptive.has(FLAG_SIZE));
+    }
+
+    #[test]
+    fn name_and_meta() {
+        let header = Header::new(Mode::Bytes, Some(5))
+            .with_name("some/dir/report.csv")
+            .with_meta(Meta { permissions: 0o640, mtime: 1_700_000_000 });
+        assert_eq!(header.name.as_deref(), Some("report.csv"));
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        assert_eq!(Header::read(&mut bytes.as_slice()).unwrap(), header);
+        let rans = header.clone().with_coder(Coder::Rans);
+        assert!(rans.has(FLAG_CODER));
+        let mut bytes = Vec::new();
+        rans.write(&mut bytes).unwrap();
+        assert_eq!(Header::read(&mut bytes.as_slice()).unwrap().coder, Coder::Rans);
+        assert!(!rans.clone().with_coder(Coder::Huffman).has(FLAG_CODER));
+        let trained = rans.with_dictionary(0xdead_beef);
+        let mut bytes = Vec::new();
+        trained.write(&mut bytes).unwrap();
+        assert_eq!(Header::read(&mut bytes.as_slice()).unwrap(), trained);
+        assert!(Header::new(Mode::Bytes, None).with_name("dir/").name.is_none());
+
+        //names with path components are rejected even with a valid checksum
+        let mut evil = header.clone();
+        evil.name = Some("../../etc/passwd".to_string());
+        let mut bytes = Vec::new();
+        evil.write(&mut bytes).unwrap();
+        assert!(Header::read(&mut bytes.as_slice()).is_err());
+    }
+
+    /// the fixed header fields followed by their checksum
+    fn with_crc(fields: &[u8]) -> Vec<u8> {
+        let mut bytes = fields.to_vec();
+        bytes.extend_from_slice(&crc32(fields).to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn rejects_foreign_and_newer() {
+        assert!(Header::read(&mut &b"plain text input"[..]).is_err());
+        assert!(Header::read(&mut &MAGIC[..]).is_err());
+        let valid = with_crc(&[0x89, b'H', b'U', b'F', VERSION, 0, 1]);
+        assert!(Header::read(&mut valid.as_slice()).is_ok());
+        let newer = with_crc(&[0x89, b'H', b'U', b'F', VERSION + 1, 0, 1]);
+        assert!(Header::read(&mut newer.as_slice()).is_err());
+        let unknown_flag = with_crc(&[0x89, b'H', b'U', b'F', VERSION, 0x80, 1]);
+        assert!(Header::read(&mut unknown_flag.as_slice()).is_err());
+        //the size flag promises 8 more bytes
+        let no_size = with_crc(&[0x89, b'H', b'U', b'F', VERSION, FLAG_SIZE, 1]);
+        assert!(Header::read(&mut no_size.as_slice()).is_err());
+    }
+
+    #[test]
+    fn checksums() {
+        let mut bytes = Vec::new();
+        Header::new(Mode::Bytes, Some(12)).write(&mut bytes).unwrap();
+        for bit in 32..bytes.len() * 8 {
+            let mut flipped = bytes.clone();
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            assert!(Header::read(&mut flipped.as_slice()).is_err());
+        }
+        let mut trailer = Vec::new();
+        write_checksum(&mut trailer, 0xdead_beef).unwrap();
+        assert!(verify_checksum(&mut trailer.as_slice(), 0xdead_beef).is_ok());
+        match verify_checksum(&mut trailer.as_slice(), 0xdead_beee) {
+            Err(Error::Integrity(_, _)) => {},
+            other => panic!("expected an integrity error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn size_check() {
+        assert!(Header::new(Mode::Bytes, Some(3)).check_size(3).is_ok());
+        assert!(Header::new(Mode::Bytes, Some(3)).check_size(4).is_err());
+        assert!(Header::new(Mode::Bytes, None).check_size(4).is_ok());
+    }
+}
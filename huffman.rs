@@ -1,75 +1,264 @@
 use crate::error::{Result, Error};
-use crate::utils::{read_u32, inc_bit};
+use crate::utils::{read_char, utf8_width, write_char};
 use crate::node::Node;
+use crate::limit::package_merge;
+use crate::bits::{BitOrder, BitReader, BitWriter};
+use crate::table::DecodeTable;
 use std::collections::HashMap;
+use std::io::{Read, Write};
 
-const ASCII_MAX: usize = u8::MAX as usize;
-const NODE_BYTES_LEN: usize = 5;
+const MAX_CODE_LEN: u8 = 64;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Debug)]
 pub enum TravRes<'n> {
-    Node(&'n Box<Node>),
-    Char(char)
+    Node(&'n Node),
+    Sym(u32)
+}
+
+/// The symbol alphabet the codes are built over.
+/// `Text` uses unicode scalar values of valid utf-8 input, `Bytes` the 256 byte values
+/// and works for any input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Text,
+    Bytes,
+}
+
+impl Mode {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Mode::Text => 0,
+            Mode::Bytes => 1,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(Mode::Text),
+            1 => Ok(Mode::Bytes),
+            _ => Err(Error::DeCompress("unknown symbol mode".to_string(), format!("{byte}"))),
+        }
+    }
+
+    /// the symbols of `data`, fails for text that is not valid utf-8
+    pub fn symbols(self, data: &[u8]) -> Result<Box<dyn Iterator<Item = u32> + '_>> {
+        match self {
+            Mode::Text => {
+                let text = std::str::from_utf8(data).map_err(|err| Error::Encoding("text mode requires utf-8 input".to_string(), err.to_string()))?;
+                Ok(Box::new(text.chars().map(|ch| ch as u32)))
+            },
+            Mode::Bytes => Ok(Box::new(data.iter().map(|b| *b as u32))),
+        }
+    }
+
+    /// appends the encoding of `sym` to `data`
+    pub fn push_symbol(self, sym: u32, data: &mut Vec<u8>) {
+        match self {
+            Mode::Text => {
+                let ch = char::from_u32(sym).unwrap_or(char::REPLACEMENT_CHARACTER);
+                let mut buf = [0u8; 4];
+                data.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+            },
+            Mode::Bytes => data.push(sym as u8),
+        }
+    }
+}
+
+/// How `decode` resolves codes: `Tree` walks the code tree one bit at a time,
+/// `Table` looks up several bits at once, see `DecodeTable`. Both give the same output.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Decoder {
+    Tree,
+    #[default]
+    Table,
+}
+
+/// a canonical prefix code, `bits` holds the code in its lowest `len` bits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Code {
+    pub bits: u64,
+    pub len: u8,
 }
 
 pub struct Huffman {
-    data: String,
+    mode: Mode,
+    data: Vec<u8>,
     bytes: Vec<u8>, 
-    frequencies: Vec<(char, u32)>,
+    frequencies: Vec<(u32, u32)>,
+    max_len: u8,
     nodes: Vec<Box<Node>>,
     size: usize,
     tree: Option<Box<Node>>,
-    tree_height: usize,
-    lookup: HashMap<char, String>,
-    offset_bit: Option<u32>
+    lengths: Vec<(u32, u8)>,
+    lookup: HashMap<u32, Code>,
+    count: u64,
+    decoder: Decoder,
+}
+
+/// orders by descending frequency, ties broken by symbol, so encoder and decoder
+/// start building the tree from the exact same queue
+fn sort_nodes(nodes: &mut [Box<Node>]) {
+    nodes.sort_by(|a, b| b.freq.cmp(&a.freq).then(a.sym.cmp(&b.sym)));
 }
 
 impl Huffman {
     pub fn from_str(data: &str) -> Self {
-        let mut map: HashMap<char, Node> = HashMap::new();
-        for ch in data.chars() {
-            map.entry(ch)
-                .and_modify(|n| (*n).freq += 1)
-                .or_insert(Node::new_node(ch, 1));
-        }
-        let mut nodes = map.into_values().map(|n| Box::new(n)).collect::<Vec<Box<Node>>>();
-        nodes.sort_by(|a, b| b.partial_cmp(a).unwrap());
-        let frequencies = nodes.iter().map(|n| (n.ch, n.freq)).collect::<Vec<(char, u32)>>();
+        Self::from_symbols(Mode::Text, data.as_bytes(), data.chars().map(|ch| ch as u32))
+    }
+
+    /// counts the raw bytes of `data`, the input does not need to be valid utf-8
+    pub fn from_raw(data: &[u8]) -> Self {
+        Self::from_symbols(Mode::Bytes, data, data.iter().map(|b| *b as u32))
+    }
+
+    /// counts `symbols` without keeping any input, for coders that write the codes themselves
+    pub fn for_symbols(mode: Mode, symbols: &[u32]) -> Self {
+        Self::from_symbols(mode, &[], symbols.iter().copied())
+    }
+
+    /// the codes of `symbols`, at most `max_len` bits long (0 for no limit)
+    pub fn with_symbols(mode: Mode, symbols: &[u32], max_len: u8) -> Result<Self> {
+        let mut huffman = Self::for_symbols(mode, symbols);
+        huffman.set_max_len(max_len)?;
+        huffman.create_tree()?;
+        huffman.codes();
+        Ok(huffman)
+    }
+
+    fn from_symbols(mode: Mode, data: &[u8], symbols: impl Iterator<Item = u32>) -> Self {
+        let mut map: HashMap<u32, Node> = HashMap::new();
+        for sym in symbols {
+            map.entry(sym)
+                .and_modify(|n| n.freq += 1)
+                .or_insert(Node::new_node(sym, 1));
+        }
+        let mut nodes = map.into_values().map(Box::new).collect::<Vec<Box<Node>>>();
+        sort_nodes(&mut nodes);
+        let frequencies = nodes.iter().map(|n| (n.sym, n.freq)).collect::<Vec<(u32, u32)>>();
+        let count = frequencies.iter().map(|(_, freq)| *freq as u64).sum();
         let size = nodes.len();
         Self {
-            data: data.to_string(),
+            mode,
+            data: data.to_vec(),
             bytes: Vec::new(),
             frequencies,
+            max_len: 0,
             nodes,
             tree: None,
-            tree_height: 0,
+            lengths: Vec::new(),
             size,
             lookup: HashMap::new(),
-            offset_bit: None,
+            count,
+            decoder: Decoder::default(),
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let mut code = Self {
-            data: String::new(),
-            bytes: bytes.to_vec(),
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::parse(bytes, None)
+    }
+
+    /// reads a stream that was encoded without table (see `encode_with_table`)
+    /// with the codes of `previous`
+    pub fn from_bytes_reusing(bytes: &[u8], previous: &Huffman) -> Result<Self> {
+        Self::parse(bytes, Some(previous))
+    }
+
+    /// reads code lengths written by `write_lengths` and assigns their canonical codes
+    pub fn from_lengths<R: Read>(reader: &mut BitReader<R>, mode: Mode, max_len: u8) -> Result<Self> {
+        let mut code = Self::empty(mode, Vec::new());
+        code.max_len = max_len;
+        code.read_lengths(reader)?;
+        code.canonical()?;
+        Ok(code)
+    }
+
+    /// canonical codes of the given (symbol, code length) pairs, as DEFLATE assigns them
+    pub fn from_code_lengths(mode: Mode, lengths: Vec<(u32, u8)>) -> Result<Self> {
+        let mut code = Self::empty(mode, Vec::new());
+        code.set_lengths(lengths)?;
+        code.canonical()?;
+        Ok(code)
+    }
+
+    fn parse(bytes: &[u8], previous: Option<&Huffman>) -> Result<Self> {
+        let mut code = Self::empty(Mode::Bytes, bytes.to_vec());
+        let mut reader = BitReader::new(bytes, BitOrder::Lsb);
+        match previous {
+            Some(previous) => code.reuse_codes(previous),
+            None => code.deserialize(&mut reader)?,
+        }
+        code.count = reader.read_bits(64)?;
+        reader.align()?;
+        code.bytes = bytes[(reader.bits_read() / 8) as usize..].to_vec();
+        Ok(code)
+    }
+
+    fn empty(mode: Mode, bytes: Vec<u8>) -> Self {
+        Self {
+            mode,
+            data: Vec::new(),
+            bytes,
             frequencies: Vec::new(),
+            max_len: 0,
             nodes: Vec::new(),
             tree: None,
-            tree_height: 0,
+            lengths: Vec::new(),
             size: 0,
             lookup: HashMap::new(),
-            offset_bit: None
-        };
-        let _ = code.deserialize();
-        code.nodes.sort_by(|a, b| b.partial_cmp(a).unwrap());
-        code.frequencies = code.nodes.iter().map(|n| (n.ch, n.freq)).collect::<Vec<(char, u32)>>();
-        code.size = code.nodes.len();
-        code
+            count: 0,
+            decoder: Decoder::default(),
+        }
     }
 
 
+    /// caps the length of every code at `max_len` bits, 0 means no limit.
+    /// Fails if the symbols of the input do not fit into codes of that length.
+    pub fn set_max_len(&mut self, max_len: u8) -> Result<()> {
+        if max_len > MAX_CODE_LEN {
+            return Err(Error::Encoding(format!("code length limit can be at most {}", MAX_CODE_LEN), format!("{max_len}")));
+        }
+        let n = self.frequencies.len();
+        if max_len > 0 && max_len < 64 && n > 1usize << max_len {
+            return Err(Error::Encoding(format!("{} symbols do not fit into codes of at most {} bits", n, max_len), String::new()));
+        }
+        self.max_len = max_len;
+        Ok(())
+    }
+
+    /// takes over the codes of `other` instead of building its own,
+    /// every symbol of the input must have a code in `other`
+    pub fn reuse_codes(&mut self, other: &Huffman) {
+        self.mode = other.mode;
+        self.max_len = other.max_len;
+        self.lengths = other.lengths.clone();
+        self.lookup = other.lookup.clone();
+    }
+
+    /// number of bits the input takes with the codes of `other`,
+    /// `None` if `other` lacks a code for one of its symbols
+    pub fn cost_with(&self, other: &Huffman) -> Option<u64> {
+        let mut bits = 0;
+        for (sym, freq) in self.frequencies.iter() {
+            bits += *freq as u64 * other.lookup.get(sym)?.len as u64;
+        }
+        Some(bits)
+    }
+
+    /// number of bits the serialized code lengths take
+    pub fn table_bits(&self) -> Result<u64> {
+        let mut writer = BitWriter::new(std::io::sink(), BitOrder::Lsb);
+        self.serialize(&mut writer)?;
+        Ok(writer.bits_written())
+    }
+
+    pub fn set_decoder(&mut self, decoder: Decoder) {
+        self.decoder = decoder;
+    }
+
     fn build(&mut self) {
+        if self.size < 2 {
+            return;
+        }
         let n = self.size - 1;
         let i = (n - 1) / 2;
         for idx in (0..i+1).rev() {
@@ -122,37 +311,68 @@ impl Huffman {
     }
 
     pub fn codes(&mut self) {
-        if let Some(tree) = self.tree.as_ref() {
-            let mut path = ['\0'; ASCII_MAX];
-            self.prepare_codes(tree.clone(), &mut path, 0);
+        if let Some(tree) = self.tree.take() {
+            self.lengths.clear();
+            if tree.is_leave() {
+                //a single symbol still needs one bit per occurrence
+                self.lengths.push((tree.sym, 1));
+            } else {
+                self.prepare_lengths(&tree, 0);
+            }
+            self.tree = Some(tree);
+            //blocks are far too small for a tree deeper than 64, its lengths always fit
+            self.canonical().expect("the lengths of a tree form a prefix code");
         }
     }
 
 
-    fn prepare_codes(&mut self, root: Box<Node>, path: &mut [char; ASCII_MAX], top: usize) {
-        if root.left.as_ref().is_some() {
-            path[top] = '0';
-            let r = root.left.as_ref().unwrap().clone();
-            self.prepare_codes(r, path, top + 1);
+    fn prepare_lengths(&mut self, root: &Node, top: u8) {
+        if let Some(left) = root.left.as_ref() {
+            self.prepare_lengths(left, top + 1);
         }
-        if root.right.as_ref().is_some() {
-            path[top] = '1';
-            let r = root.right.as_ref().unwrap().clone();
-            self.prepare_codes(r, path, top + 1);
+        if let Some(right) = root.right.as_ref() {
+            self.prepare_lengths(right, top + 1);
         }
         if root.is_leave() {
-            let mut buf = String::new();
-            for i in 0..top {
-                buf.push(path[i]);
-            }
-            self.lookup.insert(root.ch, buf);
-            if top+1 > self.tree_height {
-                self.tree_height = top+1;
+            self.lengths.push((root.sym, top));
+        }
+    }
+
+    /// assigns canonical codes: symbols sorted by code length, then by value, get
+    /// consecutive codes, so the decoder can rebuild them from the lengths alone.
+    /// Fails if the lengths run out of codes, which only corrupted lengths do.
+    fn canonical(&mut self) -> Result<()> {
+        self.lengths.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        self.lookup.clear();
+        //wide enough for a shift by all 64 bits and the code after the last one
+        let mut code: u128 = 0;
+        let mut prev_len = 0;
+        for &(sym, len) in self.lengths.iter() {
+            code <<= len - prev_len;
+            if code >> len != 0 {
+                return Err(Error::DeCompress("code lengths do not form a prefix code".to_string(), format!("{sym}")));
             }
+            self.lookup.insert(sym, Code { bits: code as u64, len });
+            code += 1;
+            prev_len = len;
         }
+        Ok(())
+    }
+
+    /// rebuilds the decoding tree from the canonical codes
+    fn canonical_tree(&self) -> Option<Box<Node>> {
+        if self.lookup.is_empty() {
+            return None;
+        }
+        let mut root = Box::new(Node::new_node(0, 0));
+        for (sym, code) in self.lookup.iter() {
+            root.insert_code(*sym, code.bits, code.len);
+        }
+        Some(root)
     }
 
-    pub fn create_tree(&mut self) {
+    /// builds the huffman tree, or the optimal length limited codes if it is deeper than `max_len`
+    pub fn create_tree(&mut self) -> Result<()> {
         self.build();
         let mut left;
         let mut right;
@@ -160,153 +380,183 @@ impl Huffman {
         while self.size > 1 {
             left = self.get_min();
             right = self.get_min();
-            top = Node::new_node('\0', left.freq + right.freq);
+            top = Node::new_node(0, left.freq + right.freq);
             top.left = Some(left);
             top.right = Some(right);
             self.insert(Box::new(top));
         }
-        self.tree = Some(self.get_min())
+        if self.size > 0 {
+            self.tree = Some(self.get_min())
+        }
+        let too_deep = self.tree.as_ref().map(|tree| tree.depth() > self.max_len as usize).unwrap_or(false);
+        if self.max_len > 0 && too_deep {
+            //the plain huffman tree is too deep, replace it by the optimal length limited one
+            self.lengths = package_merge(&self.frequencies, self.max_len)?;
+            self.canonical()?;
+            self.tree = self.canonical_tree();
+        }
+        Ok(())
     }
 
-    fn get_path(&self, ch: char) -> Option<String> {
-        self.lookup.get(&ch).cloned()
+    pub fn get_code(&self, sym: u32) -> Option<Code> {
+        self.lookup.get(&sym).copied()
     }
 
+    /// writes the code of `sym`, which has to be in the table
+    pub fn write_code<W: Write>(&self, writer: &mut BitWriter<W>, sym: u32) -> Result<()> {
+        let code = self.get_code(sym)
+            .ok_or_else(|| Error::Encoding(format!("Could not get code for {}", sym), String::new()))?;
+        writer.write_code(code.bits, code.len as u32)
+    }
+
+    /// lookup tables of the current codes, see `DecodeTable`
+    pub fn decode_table(&self) -> DecodeTable {
+        let codes = self.lookup.iter().map(|(sym, code)| (*sym, code.bits, code.len)).collect::<Vec<(u32, u64, u8)>>();
+        DecodeTable::new(&codes)
+    }
+
+    fn symbols(&self) -> Result<Box<dyn Iterator<Item = u32> + '_>> {
+        self.mode.symbols(&self.data)
+    }
+
+    fn push_symbol(&mut self, sym: u32) {
+        self.mode.push_symbol(sym, &mut self.data);
+    }
+
+    /// writes the header followed by the code of every symbol of the input,
+    /// the result is packed least significant bit first
     pub fn encode(&mut self) -> Result<()> {
-        self.serialize();
-        let start_idx = self.bytes.len();
-        self.bytes.extend_from_slice(&[0,0,0,0]);
-        let mut chs = self.data.chars();
-        let mut byte = 0u8;
-        let mut bit = 0;
-        loop {
-            if let Some(ch) = chs.next() {
-                if let Some(path) = self.get_path(ch) {
-                    for dir in path.chars() {
-                        if dir == '1' {
-                            byte |= 1 << bit;
-                        }
-                        if inc_bit(&mut bit) {
-                            self.bytes.push(byte);
-                            byte = 0;
-                        }
-                    }
-                } else {
-                    Error::Encoding(format!("Could not get path for {}", ch), String::new());
-                }
-            } else {
-                break;
-            }
-        }
+        self.encode_with_table(true)
+    }
 
-        //TODO not sure if the offset bit works if 
-        //it is a zero??
-        if byte != 0 {
-            self.bytes.push(byte);
-            self.offset_bit = Some(bit as u32);
+    /// like `encode`, but without `table` the code lengths are left out, the decoder has
+    /// to know them already (see `from_bytes_reusing`)
+    pub fn encode_with_table(&mut self, table: bool) -> Result<()> {
+        let bytes = self.write_codes(table, self.symbols()?)?;
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    /// like `encode`, but codes `symbols` instead of the input, see `for_symbols`
+    pub fn encode_symbols(&mut self, symbols: &[u32]) -> Result<()> {
+        self.bytes = self.write_codes(true, symbols.iter().copied())?;
+        Ok(())
+    }
+
+    fn write_codes(&self, table: bool, symbols: impl Iterator<Item = u32>) -> Result<Vec<u8>> {
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::Lsb);
+        if table {
+            self.serialize(&mut writer)?;
         }
-        if let Some(offset_bit) = self.offset_bit {
-            let ob = offset_bit.to_be_bytes();
-            for i in 0..4 {
-                self.bytes[start_idx + i] = ob[i];
-            }
+        writer.write_bits(self.count, 64)?;
+        writer.align()?;
+        for sym in symbols {
+            let code = self.get_code(sym)
+                .ok_or_else(|| Error::Encoding(format!("Could not get code for {}", sym), String::new()))?;
+            writer.write_code(code.bits, code.len as u32)?;
         }
-        Ok(())
+        writer.finish()
     }
 
     pub fn decode(&mut self) -> Result<()>{
-        self.create_tree();
-        if let Some(root) = self.tree.as_ref() {
-            let mut bit = 0;
-            let mut node = root;
-            let len = self.bytes.len();
-            for (i, byte) in self.bytes.iter().enumerate() {
-                while bit < 8 {
-                    let mut dir = 0;
-                    if (byte & (1 << bit)) != 0 {
-                        dir = 1;
-                    }
-                    match node.next(dir)? {
-                        TravRes::Char(ch) => {
-                            node = root;
-                            self.data.push(ch);
-                        },
-                        TravRes::Node(nd) => {node = &nd; bit += 1;},
-                    }
-                    if  i == len - 1 {
-                        if let Some(offset_bit) = self.offset_bit {
-                            if bit == offset_bit as usize {
-                                break;
-                            }
+        for sym in self.decode_symbols()? {
+            self.push_symbol(sym);
+        }
+        Ok(())
+    }
+
+    /// like `decode`, but returns the symbols instead of their encoding
+    pub fn decode_symbols(&mut self) -> Result<Vec<u32>> {
+        self.canonical()?;
+        let mut reader = BitReader::new(self.bytes.as_slice(), BitOrder::Lsb);
+        //a corrupted count must not make us reserve more than the input can hold
+        let mut decoded = Vec::with_capacity(self.count.min(self.bytes.len() as u64 * 8) as usize);
+        match self.decoder {
+            Decoder::Tree => {
+                let root = match self.canonical_tree() {
+                    Some(root) => root,
+                    None => return Ok(decoded),
+                };
+                for _ in 0..self.count {
+                    let mut node: &Node = &root;
+                    while !node.is_leave() {
+                        if let TravRes::Node(nd) = node.next(reader.read_bit()?)? {
+                            node = nd;
                         }
                     }
+                    decoded.push(node.sym);
                 }
-                bit = 0;
-            }
-           if node.is_leave() {
-                self.data.push(node.ch);
-           }
+                self.tree = Some(root);
+            },
+            Decoder::Table => {
+                let table = self.decode_table();
+                for _ in 0..self.count {
+                    decoded.push(table.decode(&mut reader)?);
+                }
+            },
         }
-        Ok(())
+        Ok(decoded)
     }
     
 
-    /// serializes the huffman codes into an array of subsets of 1 byte character and 4 bytes
-    /// frequencies in order to preserve the priority queue.
-    /// The data is preceded by 4 bytes of tree height, and by 4 bytes of huffmann codec data len
-    fn serialize(&mut self) {
-        let mut bytes = vec![0u8;8];
-        for i in 1..=self.tree_height {
-            self.current_level(&self.tree, i, &mut bytes);
-        }
-        let tree_len = bytes.len() - 8;
-        let mut idx = 0;
-        for b in (self.tree_height as u32).to_be_bytes() {
-            bytes[idx] = b;
-            idx+=1;
-        }
-        for b in (tree_len as u32).to_be_bytes() {
-            bytes[idx] = b;
-            idx += 1;
+    /// serializes the canonical code lengths: 8 bits of symbol mode, 8 bits of code length limit
+    /// (0 if unlimited), 32 bits number of symbols,
+    /// followed by one entry per symbol of its utf-8 encoding (1 to 4 bytes) and 6 bits of code length - 1.
+    /// The codes themselves are derived from the lengths, see `canonical`.
+    /// `encode` follows the table up with 64 bits of the number of coded symbols.
+    fn serialize<W: Write>(&self, writer: &mut BitWriter<W>) -> Result<()> {
+        writer.write_byte(self.mode.to_byte())?;
+        writer.write_byte(self.max_len)?;
+        self.write_lengths(writer)
+    }
+
+    /// writes the part of `serialize` after the code length limit
+    pub fn write_lengths<W: Write>(&self, writer: &mut BitWriter<W>) -> Result<()> {
+        writer.write_bits(self.lengths.len() as u64, 32)?;
+        for &(sym, len) in self.lengths.iter() {
+            write_symbol(writer, sym)?;
+            writer.write_bits(len as u64 - 1, 6)?;
         }
-        self.bytes = bytes;
+        Ok(())
     }
 
-    fn current_level(&self, root: &Option<Box<Node>>, level: usize, buffer: &mut Vec<u8>) {
-        if let Some(root) = root {
-            if level == 1 {
-                if let Some(flat) = root.flat() {
-                    buffer.extend_from_slice(&flat);
-                }
+    ///deserializes the code lengths written by `serialize`
+    fn deserialize<R: Read>(&mut self, reader: &mut BitReader<R>) -> Result<()> {
+        self.mode = Mode::from_byte(reader.read_byte()?)?;
+        self.max_len = reader.read_byte()?;
+        self.read_lengths(reader)
+    }
+
+    fn read_lengths<R: Read>(&mut self, reader: &mut BitReader<R>) -> Result<()> {
+        let limit = if self.max_len == 0 { MAX_CODE_LEN } else { self.max_len.min(MAX_CODE_LEN) };
+        let num_symbols = reader.read_bits(32)?;
+        let mut lengths = Vec::new();
+        for _ in 0..num_symbols {
+            let sym = read_symbol(reader)?;
+            let len = reader.read_bits(6)? as u8 + 1;
+            if len > limit {
+                return Err(Error::DeCompress("invalid code length".to_string(), format!("{len}")));
             }
-            if level > 1 {
-                self.current_level(&root.left, level - 1, buffer);
-                self.current_level(&root.right, level - 1, buffer);
+            lengths.push((sym, len));
+        }
+        self.set_lengths(lengths)
+    }
+
+    fn set_lengths(&mut self, lengths: Vec<(u32, u8)>) -> Result<()> {
+        let mut kraft: u128 = 0;
+        for (_, len) in lengths.iter() {
+            if *len == 0 || *len > MAX_CODE_LEN {
+                return Err(Error::DeCompress("invalid code length".to_string(), format!("{len}")));
             }
-        }  
-    }
-    ///deserializes the frequencies from bytes to Vec<Box<Node>>
-    fn deserialize(&mut self) -> Result<()> {
-        let mut input = &self.bytes[..];
-        //skip tree height
-        input = &input[4..];
-        // get length of frequencies bytes
-        let num_freq_bytes = read_u32(&input[..4])? as usize;
-        input = &input[4..];
-
-        let mut b_processed: usize = 0;
-        //parse nodes
-        while b_processed < num_freq_bytes {
-            let node = Node::from_flat(&input)?;
-            self.nodes.push(Box::new(node));
-            input = &input[NODE_BYTES_LEN..];
-            b_processed += NODE_BYTES_LEN;
-        }
-        self.offset_bit = Some(read_u32(&input[..4])?);
-        //input = &input[4..];
-        self.bytes = (&self.bytes[b_processed+8+4..]).to_vec();
-        
-        
+            kraft += 1u128 << (MAX_CODE_LEN - len);
+        }
+        //lengths that do not fit into a prefix code can only come from a corrupted header,
+        //and so does a shortest code of all 64 bits
+        let shortest = lengths.iter().map(|(_, len)| *len).min();
+        if kraft > 1u128 << MAX_CODE_LEN || shortest == Some(MAX_CODE_LEN) {
+            return Err(Error::DeCompress("code lengths do not form a prefix code".to_string(), String::new()));
+        }
+        self.lengths = lengths;
         Ok(())
     }
 
@@ -322,23 +572,61 @@ impl Huffman {
    // }
 
     pub fn data_to_bytes(&self, buffer: &mut Vec<u8>) {
-        for ch in self.data.bytes() {
-            buffer.push(ch as u8)
-        }
+        buffer.extend_from_slice(self.data.as_slice());
+    }
+
+    #[cfg(test)]
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.to_vec()
+    }
+
+    #[cfg(test)]
+    pub fn data(&self) -> Vec<u8> {
+        self.data.clone()
     }
 }
 
+/// writes a symbol in its utf-8 encoding, 1 to 4 bytes
+pub fn write_symbol<W: Write>(writer: &mut BitWriter<W>, sym: u32) -> Result<()> {
+    let mut ch = Vec::with_capacity(4);
+    write_char(sym, &mut ch);
+    for b in ch.iter() {
+        writer.write_byte(*b)?;
+    }
+    Ok(())
+}
+
+/// reads a symbol written by `write_symbol`
+pub fn read_symbol<R: Read>(reader: &mut BitReader<R>) -> Result<u32> {
+    let mut ch = [0u8; 4];
+    ch[0] = reader.read_byte()?;
+    let width = utf8_width(ch[0])?;
+    for b in ch.iter_mut().take(width).skip(1) {
+        *b = reader.read_byte()?;
+    }
+    let (ch, _) = read_char(&ch[..width])?;
+    Ok(ch as u32)
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn round_trip(huf: &mut Huffman) -> Vec<u8> {
+        huf.create_tree().unwrap();
+        huf.codes();
+        huf.encode().unwrap();
+        let mut decoded = Huffman::from_bytes(&huf.bytes()).unwrap();
+        decoded.decode().unwrap();
+        decoded.data()
+    }
+
     #[test]
     fn encode() {
      let text: &str = "abbcccddddeeeeeffffff";
-     let exp = vec![0, 0, 0, 5, 0, 0, 0, 30, 100, 0, 0, 0, 4, 101, 0, 0, 0, 5, 102, 0, 0, 0, 6, 99, 0, 0, 0, 3, 97, 0, 0, 0, 1, 98, 0, 0, 0, 2, 0, 0, 0, 3, 247, 191, 13, 64, 213, 170, 2];
+     let exp = vec![0, 0, 6, 0, 0, 0, 100, 65, 89, 96, 22, 140, 9, 97, 131, 216, 80, 1, 0, 0, 0, 0, 0, 0, 0, 247, 191, 13, 64, 213, 170, 2];
      let mut huf = Huffman::from_str(text);
-     huf.create_tree();
+     huf.create_tree().unwrap();
      huf.codes();
      let _ = huf.encode();
      assert_eq!(huf.bytes(), exp);
@@ -346,15 +634,162 @@ mod test {
 
     #[test]
     fn decode() {
-     let data = vec![0, 0, 0, 5, 0, 0, 0, 30, 100, 0, 0, 0, 4, 101, 0, 0, 0, 5, 102, 0, 0, 0, 6, 99, 0, 0, 0, 3, 97, 0, 0, 0, 1, 98, 0, 0, 0, 2, 0, 0, 0, 3, 247, 191, 13, 64, 213, 170, 2];
+     let data = vec![0, 0, 6, 0, 0, 0, 100, 65, 89, 96, 22, 140, 9, 97, 131, 216, 80, 1, 0, 0, 0, 0, 0, 0, 0, 247, 191, 13, 64, 213, 170, 2];
      let expected = String::from("abbcccddddeeeeeffffff");
-     let mut huf = Huffman::from_bytes(&data);
-     huf.decode();
-     assert_eq!(huf.data(), expected);
+     let mut huf = Huffman::from_bytes(&data).unwrap();
+     huf.decode().unwrap();
+     assert_eq!(huf.data(), expected.into_bytes());
 
     }
-}
 
+    #[test]
+    fn canonical_codes() {
+        let mut huf = Huffman::from_str("abbcccddddeeeeeffffff");
+        huf.create_tree().unwrap();
+        huf.codes();
+        let expected = [('d', 0b00, 2), ('e', 0b01, 2), ('f', 0b10, 2), ('c', 0b110, 3), ('a', 0b1110, 4), ('b', 0b1111, 4)];
+        for (ch, bits, len) in expected {
+            assert_eq!(huf.get_code(ch as u32).unwrap(), Code { bits, len });
+        }
+    }
+
+    fn header(max_len: u8, lengths: &[(char, u8)]) -> Vec<u8> {
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::Lsb);
+        writer.write_byte(0).unwrap();
+        writer.write_byte(max_len).unwrap();
+        writer.write_bits(lengths.len() as u64, 32).unwrap();
+        for (ch, len) in lengths {
+            writer.write_byte(*ch as u8).unwrap();
+            writer.write_bits(*len as u64 - 1, 6).unwrap();
+        }
+        writer.write_bits(0, 64).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn rejects_invalid_lengths() {
+        assert!(Huffman::from_bytes(&header(0, &[('a', 1), ('b', 2), ('c', 2)])).is_ok());
+        //three codes of length 1 can not be a prefix code
+        assert!(Huffman::from_bytes(&header(0, &[('a', 1), ('b', 1), ('c', 1)])).is_err());
+        //lengths above the recorded limit
+        assert!(Huffman::from_bytes(&header(2, &[('a', 1), ('b', 3), ('c', 3)])).is_err());
+        let data = header(0, &[('a', 1), ('b', 1)]);
+        assert!(Huffman::from_bytes(&data[..7]).is_err());
+        //a single code of 64 bits passes the prefix check, but no shortest code is that long
+        assert!(Huffman::from_bytes(&header(0, &[('a', 64)])).is_err());
+        assert!(Huffman::from_code_lengths(Mode::Bytes, vec![(0, 64), (1, 64)]).is_err());
+        assert!(Huffman::from_code_lengths(Mode::Bytes, vec![(0, 1), (1, 64)]).is_ok());
+    }
+
+    #[test]
+    fn length_limited_round_trip() {
+        //fibonacci frequencies give the deepest possible tree
+        let (mut a, mut b) = (1usize, 1usize);
+        let mut data = Vec::new();
+        for sym in 0..25u8 {
+            data.extend(std::iter::repeat(sym).take(a));
+            let next = a + b;
+            a = b;
+            b = next;
+        }
+        let mut unlimited = Huffman::from_raw(&data);
+        unlimited.create_tree().unwrap();
+        assert!(unlimited.tree.as_ref().unwrap().depth() > 12);
+
+        let mut huf = Huffman::from_raw(&data);
+        huf.set_max_len(12).unwrap();
+        huf.create_tree().unwrap();
+        huf.codes();
+        assert!(huf.lengths.iter().all(|(_, len)| *len <= 12));
+        assert_eq!(round_trip(&mut huf), data);
+        assert_eq!(huf.bytes()[1], 12);
+        assert!(Huffman::from_raw(&data).set_max_len(4).is_err());
+    }
+
+    #[test]
+    fn decoders_agree() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        //fibonacci like frequencies force codes longer than one table lookup
+        let mut skewed = Vec::new();
+        let (mut a, mut b) = (1usize, 1usize);
+        for sym in 0..20u8 {
+            skewed.extend(std::iter::repeat(sym).take(a));
+            let next = a + b;
+            a = b;
+            b = next;
+        }
+        for data in [text, skewed] {
+            let mut huf = Huffman::from_raw(&data);
+            huf.create_tree().unwrap();
+            huf.codes();
+            huf.encode().unwrap();
+            let mut outputs = Vec::new();
+            for decoder in [Decoder::Tree, Decoder::Table] {
+                let mut decoded = Huffman::from_bytes(&huf.bytes()).unwrap();
+                decoded.set_decoder(decoder);
+                decoded.decode().unwrap();
+                outputs.push(decoded.data());
+            }
+            assert_eq!(outputs[0], data);
+            assert_eq!(outputs[1], data);
+        }
+    }
 
+    #[test]
+    fn reused_codes() {
+        let mut first = Huffman::from_raw(b"abracadabra");
+        first.create_tree().unwrap();
+        first.codes();
+        let second = Huffman::from_raw(b"cabbage");
+        assert_eq!(second.cost_with(&first), None);
+        let mut second = Huffman::from_raw(b"barbacadra");
+        assert!(second.cost_with(&first).is_some());
+        second.reuse_codes(&first);
+        second.encode_with_table(false).unwrap();
+        let with_table = first.table_bits().unwrap();
+        assert!(with_table > 0);
+
+        assert!(Huffman::from_bytes(&second.bytes()).is_err());
+        let mut decoded = Huffman::from_bytes_reusing(&second.bytes(), &first).unwrap();
+        decoded.decode().unwrap();
+        assert_eq!(decoded.data(), b"barbacadra");
+    }
 
+    #[test]
+    fn bytes_round_trip() {
+        let mut data: Vec<u8> = (0..=255u8).collect();
+        data.extend((0..4096u32).map(|i| (i * i % 251) as u8));
+        data.extend_from_slice(&[0xff, 0xfe, 0x00, 0x80, 0xc3, 0x28]);
+        let mut huf = Huffman::from_raw(&data);
+        assert_eq!(round_trip(&mut huf), data);
+        assert_eq!(huf.bytes()[0], Mode::Bytes.to_byte());
+    }
 
+    #[test]
+    fn unicode_round_trip() {
+        let text = "Grüße aus Köln, ça va? 日本語のテキスト, Ελληνικά, emoji 😀😀 and plain ascii.";
+        let mut huf = Huffman::from_str(text);
+        assert_eq!(round_trip(&mut huf), text.as_bytes());
+        //invalid text is an error, not text without symbols
+        assert!(Mode::Text.symbols(&[b'a', 0xff]).is_err());
+        assert_eq!(Mode::Text.symbols("日本".as_bytes()).unwrap().collect::<Vec<u32>>(), [0x65e5, 0x672c]);
+    }
+
+    #[test]
+    fn single_symbol_round_trip() {
+        let data = vec![0u8; 13];
+        assert_eq!(round_trip(&mut Huffman::from_raw(&data)), data);
+    }
+
+    #[test]
+    fn empty_round_trip() {
+        assert_eq!(round_trip(&mut Huffman::from_raw(&[])), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn zero_last_byte_round_trip() {
+        //'a' gets the all zero code and ends the stream on a fresh byte
+        let data = b"aaaaaaaabbbbccda".to_vec();
+        assert_eq!(round_trip(&mut Huffman::from_raw(&data)), data);
+    }
+}
@@ -1,24 +1,139 @@
-use std::io;
 use crate::error::{Result as R, Error};
+use crate::huffman::Decoder;
+use crate::stream::{BLOCK_SIZE, Options};
+use crate::tokens::Alphabet;
+use crate::lz77::Settings;
+use crate::deflate::Container;
+use crate::coder::Coder;
+use crate::dictionary::Dictionary;
+use std::sync::Arc;
 
 
 pub fn usage(prog: &str) {
     println!("Usage: {prog} [Commands] [flags]");
+    println!("       {prog} list|extract|add|delete <archive> [paths] [flags]");
+    println!("       {prog} train <dictionary> <samples> [-t] [-l <bits>]");
+    println!("Archive commands (use './list' for a file called 'list')");
+    println!("    list <archive>..................print every member with its original and compressed size");
+    println!("    extract <archive> [members].....extract all or the named members, to stdout with print_out");
+    println!("    add <archive> <paths>...........append files and directories, replacing members of the same path");
+    println!("    delete <archive> <members>......remove members and compact the archive");
+    println!("    train <dictionary> <samples>....build a code table from sample files and directories, for --dict");
     println!("Commands");
-    println!("    in_file.............the source file. If a file with the extension '.huf' is encountered it is a 'decompression' operation. If this command is skipped, it is assumed that the source is inserted via stdout or a pipe.");
-    println!("    print_out...........writes the output to stdout");
+    println!("    in_file.............the source file. Compressed input is recognized by its header and decompressed, anything else is compressed. If this command is skipped, it is assumed that the source is inserted via stdout or a pipe.");
+    println!("                        A directory is stored as an archive with everything below it, an archive is extracted. Names after an archive select the members to extract");
+    println!("    print_out...........writes the output to stdout. Data decompressed from stdin always goes to stdout");
+    println!("    -d/--decompress.....decompress, fails if the input is not a huf file");
+    println!("    -c/--compress.......compress, even if the input already is a huf file");
+    println!("    -t/--text...........compress utf-8 characters instead of raw bytes (the input must be valid utf-8)");
+    println!("    -l/--max-len <bits>.limit the length of every code to <bits> (1 to 64), no limit if skipped");
+    println!("    --decoder <tree|table>..how to decode, 'table' (default) resolves several bits per lookup, 'tree' walks the code tree bit by bit");
+    println!("    -b/--block-size <n>.number of input bytes coded with one table, accepts a 'k' or 'm' suffix (default 256k)");
+    println!("    --no-reuse..........give every block a fresh table, instead of reusing the previous one when that is smaller");
+    println!("    -o/--order1.........code blocks with one table per preceding symbol where that is smaller");
+    println!("    -w/--words..........code blocks over whole words and separators where that is smaller");
+    println!("    -g/--grams..........code blocks over frequent byte sequences of up to 8 bytes where that is smaller");
+    println!("    -z/--lz.............replace repeated phrases by references to earlier ones (LZ77) where that is smaller");
+    println!("    --window <n>........how far back --lz looks for repetitions, accepts 'k' or 'm' (default 32k, at most 16m and never past the start of the block)");
+    println!("    --effort <n>........how many earlier candidates --lz tries per position (default 32)");
+    println!("    -B/--bwt............block sort (Burrows-Wheeler, move-to-front, runs of zeros) before coding where that is smaller, best with large blocks");
+    println!("    --coder <huffman|rans>..entropy coder of the blocks, 'rans' spends fractions of a bit on frequent symbols (default huffman, not with -a)");
+    println!("    -D/--dict <file>....code blocks with the table of a trained dictionary where that is smaller, needed again to decompress");
+    println!("    -j/--threads <n>....code and decode <n> blocks at once on their own threads, 0 for one per core (default 1)");
+    println!("    -I/--index..........keep every block on its own table and append a block index, so ranges can be decompressed");
+    println!("    -r/--range <from>:<to>..print the original bytes from <from> up to <to> (or the end) of a file compressed with --index, accepts 'k' or 'm'");
+    println!("    --gzip..............write gzip files (.gz) that any gzip can read, --lz sets the effort. gzip input is recognized and decompressed");
+    println!("    --zlib..............read or write zlib streams (.zz)");
+    println!("    --deflate...........read or write bare DEFLATE streams (.deflate)");
+    println!("    -a/--adaptive.......one pass adaptive huffman coding, no table is stored and no block is read ahead");
+    println!("    -n/--no-name........do not store the file name, permissions and mtime when compressing, do not restore them when decompressing");
+    println!("    -N/--name...........store and restore the file name, permissions and mtime (default)");
+    println!("    -A/--archive <file>.store all given files and directories in the archive <file>");
+    println!("    -s/--solid..........new archives code small files of the same kind together with one table per block");
+    println!("    -C/--directory <dir>..extract archives into <dir> instead of the current directory");
     println!("    -h/--help...........print this usage");
 }
 
+/// archive subcommands, given as the first argument
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    List,
+    Extract,
+    Add,
+    Delete,
+    Train,
+}
+
 //NOTE if in is stdin then always to stdout - or implement a guard and arg 
 //that requires out_file to be set
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Args {
     pub program: String,
     pub in_file: Option<String>,
     pub decompress: bool,
-    pub data: Option<String>,
+    pub compress: bool,
     pub print_out: bool,
+    pub text: bool,
+    pub max_len: u8,
+    pub decoder: Decoder,
+    pub block_size: usize,
+    pub reuse_tables: bool,
+    pub context: bool,
+    pub tokens: Option<Alphabet>,
+    pub lz: Option<Settings>,
+    pub bwt: bool,
+    pub coder: Coder,
+    pub dictionary: Option<Arc<Dictionary>>,
+    /// blocks coded at once, at least 1
+    pub threads: usize,
+    /// write a block index after the data
+    pub index: bool,
+    /// the original bytes to print, the end is exclusive
+    pub range: Option<(u64, u64)>,
+    /// write or read DEFLATE data in this framing instead of a huf file
+    pub container: Option<Container>,
+    pub adaptive: bool,
+    pub keep_name: bool,
+    /// further inputs when creating an archive, or the members to extract
+    pub paths: Vec<String>,
+    pub archive: Option<String>,
+    pub directory: Option<String>,
+    pub command: Option<Command>,
+    pub solid: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            program: String::new(),
+            in_file: None,
+            decompress: false,
+            compress: false,
+            print_out: false,
+            text: false,
+            max_len: 0,
+            decoder: Decoder::default(),
+            block_size: BLOCK_SIZE,
+            reuse_tables: true,
+            context: false,
+            tokens: None,
+            lz: None,
+            bwt: false,
+            coder: Coder::default(),
+            dictionary: None,
+            threads: 1,
+            index: false,
+            range: None,
+            container: None,
+            adaptive: false,
+            keep_name: true,
+            paths: Vec::new(),
+            archive: None,
+            directory: None,
+            command: None,
+            solid: false,
+        }
+    }
 }
 
 
@@ -28,63 +143,213 @@ impl Args {
         let mut args = Args::default();
         let mut args_in = std::env::args();
         args.program = args_in.next().unwrap();
-        let args_len = args_in.len();
-        
-        if args_len == 0 {
-            //try stdin
-            args.data = Some(try_read_stdin().map_err(|err| {
-                usage(args.program.as_str());
-                return Error::Args("".to_string(), err.to_string());
-            })?);
-        }
 
-        if let Some(next) = args_in.next() {
+        while let Some(next) = args_in.next() {
             if next == "-h" || next == "--help" {
                 usage(args.program.as_str());
                 std::process::exit(1);
             }
-             
-            if args_len == 1 {
-                if arg_is_print(next.as_str()) {
-                    //then check stdin
-                args.data = Some(try_read_stdin().map_err(|err| {
+            if next == "-d" || next == "--decompress" {
+                args.decompress = true;
+            } else if next == "-c" || next == "--compress" {
+                args.compress = true;
+            } else if next == "-t" || next == "--text" {
+                args.text = true;
+            } else if next == "-l" || next == "--max-len" {
+                args.max_len = args_in.next()
+                    .and_then(|len| len.parse::<u8>().ok())
+                    .filter(|len| *len > 0)
+                    .ok_or_else(|| {
+                        usage(args.program.as_str());
+                        Error::Args(format!("'{}' expects a code length in bits", next), String::new())
+                    })?;
+            } else if next == "-b" || next == "--block-size" {
+                args.block_size = args_in.next()
+                    .and_then(|size| parse_size(size.as_str()))
+                    .filter(|size| *size > 0)
+                    .ok_or_else(|| {
+                        usage(args.program.as_str());
+                        Error::Args(format!("'{}' expects a size like '4096', '64k' or '1m'", next), String::new())
+                    })?;
+            } else if next == "-o" || next == "--order1" {
+                args.context = true;
+            } else if next == "-z" || next == "--lz" {
+                args.lz.get_or_insert_with(Settings::default);
+            } else if next == "--window" || next == "--effort" {
+                let value = args_in.next()
+                    .and_then(|value| parse_size(value.as_str()))
+                    .filter(|value| *value > 0)
+                    .ok_or_else(|| {
+                        usage(args.program.as_str());
+                        Error::Args(format!("'{}' expects a positive number", next), String::new())
+                    })?;
+                let lz = args.lz.get_or_insert_with(Settings::default);
+                if next == "--window" {
+                    lz.window = value;
+                } else {
+                    lz.effort = value;
+                }
+            } else if next == "-w" || next == "--words" {
+                args.tokens = Some(Alphabet::Words);
+            } else if next == "-g" || next == "--grams" {
+                args.tokens = Some(Alphabet::Grams);
+            } else if next == "-B" || next == "--bwt" {
+                args.bwt = true;
+            } else if next == "-j" || next == "--threads" {
+                args.threads = args_in.next()
+                    .and_then(|threads| threads.parse::<usize>().ok())
+                    .ok_or_else(|| {
+                        usage(args.program.as_str());
+                        Error::Args(format!("'{}' expects a number of threads", next), String::new())
+                    })?;
+                if args.threads == 0 {
+                    args.threads = std::thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1);
+                }
+            } else if next == "-I" || next == "--index" {
+                args.index = true;
+            } else if next == "-r" || next == "--range" {
+                args.range = Some(args_in.next().and_then(|range| parse_range(range.as_str())).ok_or_else(|| {
                     usage(args.program.as_str());
-                    return Error::Args("".to_string(), err.to_string());
-                    })?);
+                    Error::Args(format!("'{}' expects a range like '4096:8192', '1m:' or ':64k'", next), String::new())
+                })?);
+            } else if next == "--gzip" {
+                args.container = Some(Container::Gzip);
+            } else if next == "--zlib" {
+                args.container = Some(Container::Zlib);
+            } else if next == "--deflate" {
+                args.container = Some(Container::Raw);
+            } else if next == "-a" || next == "--adaptive" {
+                args.adaptive = true;
+            } else if next == "-n" || next == "--no-name" {
+                args.keep_name = false;
+            } else if next == "-N" || next == "--name" {
+                args.keep_name = true;
+            } else if next == "-A" || next == "--archive" || next == "-C" || next == "--directory" {
+                let value = args_in.next().ok_or_else(|| {
+                    usage(args.program.as_str());
+                    Error::Args(format!("'{}' expects a path", next), String::new())
+                })?;
+                if next == "-A" || next == "--archive" {
+                    args.archive = Some(value);
                 } else {
-                        //must be a file
-                    args.decompress = next.ends_with(".huf");
-                    args.in_file = Some(next);
+                    args.directory = Some(value);
                 }
-                return Ok(args)
-            };
-            if args_len == 2 {
-                //expects file first
+            } else if next == "-s" || next == "--solid" {
+                args.solid = true;
+            } else if next == "--no-reuse" {
+                args.reuse_tables = false;
+            } else if next == "--decoder" {
+                args.decoder = match args_in.next().as_deref() {
+                    Some("tree") => Decoder::Tree,
+                    Some("table") => Decoder::Table,
+                    _ => {
+                        usage(args.program.as_str());
+                        return Err(Error::Args("'--decoder' expects 'tree' or 'table'".to_string(), String::new()));
+                    },
+                };
+            } else if next == "-D" || next == "--dict" {
+                let path = args_in.next().ok_or_else(|| {
+                    usage(args.program.as_str());
+                    Error::Args(format!("'{}' expects a dictionary file", next), String::new())
+                })?;
+                let mut file = std::fs::File::open(path.as_str()).map_err(|err| Error::Args(format!("could not open dictionary '{}'", path), err.to_string()))?;
+                args.dictionary = Some(Arc::new(Dictionary::read(&mut file)?));
+            } else if next == "--coder" {
+                args.coder = match args_in.next().as_deref() {
+                    Some("huffman") => Coder::Huffman,
+                    Some("rans") => Coder::Rans,
+                    _ => {
+                        usage(args.program.as_str());
+                        return Err(Error::Args("'--coder' expects 'huffman' or 'rans'".to_string(), String::new()));
+                    },
+                };
+            } else if arg_is_print(next.as_str()) {
+                args.print_out = true;
+            } else if args.in_file.is_none() && args.command.is_none() && command(next.as_str()).is_some() {
+                args.command = command(next.as_str());
+            } else if args.in_file.is_none() {
+                //must be a file
                 args.in_file = Some(next);
-                //next argument expected to be stdout
-                if let Some(last) = args_in.next() {
-                    args.print_out = arg_is_print(last.as_str());
-                }
+            } else {
+                args.paths.push(next);
             }
-        } else {
-            //error - usage
         }
-        println!("{:?}", args_in);
-        println!("{:?}", args);
+
+        if args.compress && args.decompress {
+            usage(args.program.as_str());
+            return Err(Error::Args("'--compress' and '--decompress' exclude each other".to_string(), String::new()));
+        }
         Ok(args)
     }
+
+    /// the block settings of `stream::compress`
+    pub fn options(&self) -> Options {
+        Options {
+            text: self.text,
+            max_len: self.max_len,
+            block_size: self.block_size,
+            reuse_tables: self.reuse_tables,
+            context: self.context,
+            tokens: self.tokens,
+            lz: self.lz,
+            bwt: self.bwt,
+            coder: self.coder,
+            dictionary: self.dictionary.clone(),
+            threads: self.threads,
+        }
+    }
+}
+
+fn command(arg: &str) -> Option<Command> {
+    match arg {
+        "list" => Some(Command::List),
+        "extract" => Some(Command::Extract),
+        "add" => Some(Command::Add),
+        "delete" => Some(Command::Delete),
+        "train" => Some(Command::Train),
+        _ => None,
+    }
 }
 
 fn arg_is_print(arg: &str) -> bool {
     arg == "1" || arg == "-" || arg == "stdout" || arg == "-p" || arg == "--print" 
 }
 
-fn try_read_stdin() -> Result<String, std::io::Error> {
-    let stdin = io::stdin();
-    let mut data = String::new();
-    for line in stdin.lines() {
-        data.push_str(line?.as_str());
-    }
-    Ok(data)
+/// parses '<from>:<to>' with sizes like `parse_size`, either side may be left out
+fn parse_range(arg: &str) -> Option<(u64, u64)> {
+    let (from, to) = arg.split_once(':')?;
+    let from = if from.is_empty() { 0 } else { parse_size(from)? as u64 };
+    let to = if to.is_empty() { u64::MAX } else { parse_size(to)? as u64 };
+    Some((from, to))
+}
+
+/// parses a number of bytes with an optional 'k' (KiB) or 'm' (MiB) suffix
+fn parse_size(arg: &str) -> Option<usize> {
+    let lower = arg.to_ascii_lowercase();
+    let (num, unit) = if let Some(num) = lower.strip_suffix('k') {
+        (num, 1024)
+    } else if let Some(num) = lower.strip_suffix('m') {
+        (num, 1024 * 1024)
+    } else {
+        (lower.as_str(), 1)
+    };
+    num.parse::<usize>().ok()?.checked_mul(unit)
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("64k"), Some(64 * 1024));
+        assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("k"), None);
+        assert_eq!(parse_size("12x"), None);
+        assert_eq!(parse_range("4k:8k"), Some((4096, 8192)));
+        assert_eq!(parse_range("100:"), Some((100, u64::MAX)));
+        assert_eq!(parse_range(":1m"), Some((0, 1024 * 1024)));
+        assert_eq!(parse_range("100"), None);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::error::{Result, Error};
+
+/// an entry of the package-merge lists, either a single symbol (`leaf`) or a
+/// package of two cheaper entries of the previous list
+struct Item {
+    weight: u64,
+    leaf: Option<usize>,
+    children: Option<(usize, usize)>,
+}
+
+/// computes optimal code lengths that do not exceed `max_len` with the package-merge algorithm.
+/// Takes (symbol, frequency) pairs and returns (symbol, code length) pairs.
+pub fn package_merge(frequencies: &[(u32, u32)], max_len: u8) -> Result<Vec<(u32, u8)>> {
+    let n = frequencies.len();
+    if max_len == 0 || (max_len < 64 && n > 1usize << max_len) {
+        return Err(Error::Encoding(format!("{} symbols do not fit into codes of at most {} bits", n, max_len), String::new()));
+    }
+    match n {
+        0 => return Ok(Vec::new()),
+        1 => return Ok(vec![(frequencies[0].0, 1)]),
+        _ => {}
+    }
+
+    let mut order = (0..n).collect::<Vec<usize>>();
+    order.sort_by(|a, b| frequencies[*a].1.cmp(&frequencies[*b].1).then(frequencies[*a].0.cmp(&frequencies[*b].0)));
+
+    let mut arena: Vec<Item> = order.iter()
+        .map(|idx| Item { weight: frequencies[*idx].1 as u64, leaf: Some(*idx), children: None })
+        .collect();
+    let leaves = (0..n).collect::<Vec<usize>>();
+    let mut current = leaves.clone();
+    for _ in 1..max_len {
+        let mut packages = Vec::with_capacity(current.len() / 2);
+        for pair in current.chunks_exact(2) {
+            arena.push(Item {
+                weight: arena[pair[0]].weight + arena[pair[1]].weight,
+                leaf: None,
+                children: Some((pair[0], pair[1])),
+            });
+            packages.push(arena.len() - 1);
+        }
+        current = merge(&arena, &leaves, &packages);
+    }
+
+    //every occurrence of a symbol in the cheapest 2n - 2 entries adds one bit to its code
+    let mut lengths = vec![0u8; n];
+    let mut stack = current[..2 * n - 2].to_vec();
+    while let Some(idx) = stack.pop() {
+        let item = &arena[idx];
+        if let Some(leaf) = item.leaf {
+            lengths[leaf] += 1;
+        }
+        if let Some((left, right)) = item.children {
+            stack.push(left);
+            stack.push(right);
+        }
+    }
+    Ok(lengths.into_iter().enumerate().map(|(idx, len)| (frequencies[idx].0, len)).collect())
+}
+
+/// merges two lists that are sorted by weight, on ties leaves go first
+fn merge(arena: &[Item], leaves: &[usize], packages: &[usize]) -> Vec<usize> {
+    let mut merged = Vec::with_capacity(leaves.len() + packages.len());
+    let (mut l, mut p) = (0, 0);
+    while l < leaves.len() || p < packages.len() {
+        let take_leaf = p == packages.len()
+            || (l < leaves.len() && arena[leaves[l]].weight <= arena[packages[p]].weight);
+        if take_leaf {
+            merged.push(leaves[l]);
+            l += 1;
+        } else {
+            merged.push(packages[p]);
+            p += 1;
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kraft_ok(lengths: &[(u32, u8)]) -> bool {
+        lengths.iter().map(|(_, len)| 1u128 << (64 - *len)).sum::<u128>() <= 1u128 << 64
+    }
+
+    #[test]
+    fn unlimited_is_huffman() {
+        let freqs = vec![(97, 1), (98, 2), (99, 3), (100, 4), (101, 5), (102, 6)];
+        let mut lengths = package_merge(&freqs, 32).unwrap();
+        lengths.sort();
+        assert_eq!(lengths, vec![(97, 4), (98, 4), (99, 3), (100, 2), (101, 2), (102, 2)]);
+    }
+
+    #[test]
+    fn limits_fibonacci() {
+        let mut fib = vec![1u32, 1];
+        while fib.len() < 30 {
+            let next = fib[fib.len() - 1] + fib[fib.len() - 2];
+            fib.push(next);
+        }
+        let freqs = fib.iter().enumerate().map(|(i, f)| (i as u32, *f)).collect::<Vec<(u32, u32)>>();
+        for max_len in [5u8, 8, 12, 15] {
+            let lengths = package_merge(&freqs, max_len).unwrap();
+            assert_eq!(lengths.len(), freqs.len());
+            assert!(lengths.iter().all(|(_, len)| *len >= 1 && *len <= max_len));
+            assert!(kraft_ok(&lengths));
+        }
+    }
+
+    #[test]
+    fn too_short_limit() {
+        let freqs = (0..5).map(|i| (i, 1)).collect::<Vec<(u32, u32)>>();
+        assert!(package_merge(&freqs, 2).is_err());
+        let lengths = package_merge(&freqs, 3).unwrap();
+        assert!(kraft_ok(&lengths));
+        assert_eq!(package_merge(&[(7, 3)], 1).unwrap(), vec![(7, 1)]);
+    }
+}
@@ -0,0 +1,282 @@
+use crate::error::{Result, Error};
+use crate::huffman::{Huffman, Mode};
+use crate::bits::{BitOrder, BitReader, BitWriter};
+
+/// shortest match worth a length and distance
+const MIN_MATCH: usize = 3;
+/// longest match, its length bucket stays below 16
+const MAX_MATCH: usize = 258;
+/// largest window, keeps every distance bucket below 48
+pub const MAX_WINDOW: usize = 16 * 1024 * 1024;
+const HASH_BITS: u32 = 15;
+const NONE: u32 = u32::MAX;
+/// literals are the symbols 0 to 255, lengths follow
+const FIRST_LENGTH: u32 = 256;
+
+/// how hard the match finder looks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    /// how far back a match may start
+    pub window: usize,
+    /// how many earlier positions with the same hash are tried per match
+    pub effort: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            window: 32 * 1024,
+            effort: 32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Token {
+    Literal(u8),
+    Match { len: usize, dist: usize },
+}
+
+/// Splits `value` into a bucket and its position in the bucket: 0 to 3 get a bucket each,
+/// after that every power of two is split into two buckets. Returns bucket, extra bits, extra.
+pub fn bucket(value: u32) -> (u32, u32, u32) {
+    if value < 4 {
+        return (value, 0, 0);
+    }
+    let log = 31 - value.leading_zeros();
+    let extra_bits = log - 1;
+    (2 * log + ((value >> extra_bits) & 1), extra_bits, value & ((1 << extra_bits) - 1))
+}
+
+/// first value and number of extra bits of `bucket`
+pub fn bucket_base(bucket: u32) -> (u64, u32) {
+    if bucket < 4 {
+        return (bucket as u64, 0);
+    }
+    let extra_bits = bucket / 2 - 1;
+    ((2 | (bucket as u64 & 1)) << extra_bits, extra_bits)
+}
+
+/// Hash chains over the positions of a block: `head` holds the last position of every hash
+/// of 3 bytes, `prev` the position before it with the same hash, for the last `window` positions.
+struct Finder {
+    head: Vec<u32>,
+    prev: Vec<u32>,
+    mask: usize,
+    settings: Settings,
+}
+
+impl Finder {
+    /// the chains for `len` bytes, matches never reach further back than that
+    fn new(settings: Settings, len: usize) -> Self {
+        let ring = settings.window.min(len).max(1).next_power_of_two();
+        Self {
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; ring],
+            mask: ring - 1,
+            settings,
+        }
+    }
+
+    fn hash(data: &[u8], pos: usize) -> usize {
+        let key = (data[pos] as u32) << 16 | (data[pos + 1] as u32) << 8 | data[pos + 2] as u32;
+        (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= data.len() {
+            let hash = Self::hash(data, pos);
+            self.prev[pos & self.mask] = self.head[hash];
+            self.head[hash] = pos as u32;
+        }
+    }
+
+    /// the longest earlier match for `pos` as (length, distance), length 0 if there is none
+    fn longest(&self, data: &[u8], pos: usize) -> (usize, usize) {
+        let max = (data.len() - pos).min(MAX_MATCH);
+        if max < MIN_MATCH {
+            return (0, 0);
+        }
+        let (mut best, mut dist) = (0, 0);
+        let mut candidate = self.head[Self::hash(data, pos)];
+        let mut steps = self.settings.effort;
+        while candidate != NONE && steps > 0 {
+            let start = candidate as usize;
+            if pos - start > self.settings.window {
+                break;
+            }
+            //a match can only be longer if it agrees on the byte after the best one
+            if data[start + best.min(max - 1)] == data[pos + best.min(max - 1)] {
+                let len = data[start..start + max].iter().zip(data[pos..pos + max].iter()).take_while(|(a, b)| a == b).count();
+                if len > best {
+                    best = len;
+                    dist = pos - start;
+                    if len == max {
+                        break;
+                    }
+                }
+            }
+            candidate = self.prev[start & self.mask];
+            steps -= 1;
+        }
+        if best < MIN_MATCH { (0, 0) } else { (best, dist) }
+    }
+}
+
+/// Greedy parsing with one step of lazy matching: a match is put off by a literal if the
+/// next position starts a longer one. The bytes before `start` are only matched against.
+pub fn parse(data: &[u8], start: usize, settings: Settings) -> Vec<Token> {
+    let mut finder = Finder::new(settings, data.len());
+    for pos in 0..start {
+        finder.insert(data, pos);
+    }
+    let mut tokens = Vec::new();
+    let mut pos = start;
+    while pos < data.len() {
+        let (len, dist) = finder.longest(data, pos);
+        finder.insert(data, pos);
+        if len == 0 || (pos + 1 < data.len() && finder.longest(data, pos + 1).0 > len) {
+            tokens.push(Token::Literal(data[pos]));
+            pos += 1;
+            continue;
+        }
+        tokens.push(Token::Match { len, dist });
+        for next in pos + 1..pos + len {
+            finder.insert(data, next);
+        }
+        pos += len;
+    }
+    tokens
+}
+
+/// Compresses `data` with LZ77 and codes literals and match lengths with one huffman table,
+/// match distances with a second one. Lengths and distances are coded by their bucket
+/// (see `bucket`) followed by the extra bits.
+///
+/// Written are 8 bits of code length limit, the lengths of both tables (see `Huffman::write_lengths`),
+/// 64 bits number of literals and matches and their codes.
+pub fn encode(data: &[u8], settings: Settings, max_len: u8) -> Result<Vec<u8>> {
+    if settings.window < MIN_MATCH || settings.window > MAX_WINDOW || settings.effort == 0 {
+        return Err(Error::Compress(format!("the window has to be between {} and {} bytes and the effort at least 1", MIN_MATCH, MAX_WINDOW), format!("{:?}", settings)));
+    }
+    let tokens = parse(data, 0, settings);
+    let mut lit_len = Vec::with_capacity(tokens.len());
+    let mut distances = Vec::new();
+    for token in tokens.iter() {
+        match *token {
+            Token::Literal(b) => lit_len.push(b as u32),
+            Token::Match { len, dist } => {
+                lit_len.push(FIRST_LENGTH + bucket((len - MIN_MATCH) as u32).0);
+                distances.push(bucket(dist as u32 - 1).0);
+            },
+        }
+    }
+    let lit_len = Huffman::with_symbols(Mode::Bytes, &lit_len, max_len)?;
+    let distances = Huffman::with_symbols(Mode::Bytes, &distances, max_len)?;
+
+    let mut writer = BitWriter::new(Vec::new(), BitOrder::Lsb);
+    writer.write_byte(max_len)?;
+    lit_len.write_lengths(&mut writer)?;
+    distances.write_lengths(&mut writer)?;
+    writer.write_bits(tokens.len() as u64, 64)?;
+    for token in tokens.iter() {
+        match *token {
+            Token::Literal(b) => lit_len.write_code(&mut writer, b as u32)?,
+            Token::Match { len, dist } => {
+                let (sym, extra_bits, extra) = bucket((len - MIN_MATCH) as u32);
+                lit_len.write_code(&mut writer, FIRST_LENGTH + sym)?;
+                writer.write_bits(extra as u64, extra_bits)?;
+                let (sym, extra_bits, extra) = bucket(dist as u32 - 1);
+                distances.write_code(&mut writer, sym)?;
+                writer.write_bits(extra as u64, extra_bits)?;
+            },
+        }
+    }
+    writer.finish()
+}
+
+/// Decompresses a stream written by `encode`.
+pub fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = BitReader::new(bytes, BitOrder::Lsb);
+    let max_len = reader.read_byte()?;
+    let lit_len = Huffman::from_lengths(&mut reader, Mode::Bytes, max_len)?.decode_table();
+    let distances = Huffman::from_lengths(&mut reader, Mode::Bytes, max_len)?.decode_table();
+    let count = reader.read_bits(64)?;
+    //every code takes at least one bit
+    if count > (bytes.len() as u64 * 8).saturating_sub(reader.bits_read()) {
+        return Err(Error::DeCompress("symbol count exceeds the coded data".to_string(), format!("{count}")));
+    }
+    let mut data = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let sym = lit_len.decode(&mut reader)?;
+        if sym < FIRST_LENGTH {
+            data.push(sym as u8);
+            continue;
+        }
+        let len = read_bucket(&mut reader, sym - FIRST_LENGTH, 16)? as usize + MIN_MATCH;
+        let sym = distances.decode(&mut reader)?;
+        let dist = read_bucket(&mut reader, sym, 48)? as usize + 1;
+        if dist > data.len() {
+            return Err(Error::DeCompress("match reaches before the start of the block".to_string(), format!("{dist}")));
+        }
+        //a match may overlap the bytes it produces, so copy one by one
+        let start = data.len() - dist;
+        for idx in start..start + len {
+            data.push(data[idx]);
+        }
+    }
+    Ok(data)
+}
+
+fn read_bucket<R: std::io::Read>(reader: &mut BitReader<R>, bucket: u32, buckets: u32) -> Result<u64> {
+    if bucket >= buckets {
+        return Err(Error::DeCompress("invalid length or distance code".to_string(), format!("{bucket}")));
+    }
+    let (base, extra_bits) = bucket_base(bucket);
+    Ok(base + reader.read_bits(extra_bits)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        for value in (0..70_000).chain([MAX_WINDOW as u32 - 1]) {
+            let (bucket, extra_bits, extra) = bucket(value);
+            assert_eq!(bucket_base(bucket), ((value - extra) as u64, extra_bits));
+            assert!(extra < 1 << extra_bits || extra_bits == 0);
+        }
+        assert_eq!(bucket((MAX_MATCH - MIN_MATCH) as u32).0, 15);
+        assert_eq!(bucket(MAX_WINDOW as u32 - 1).0, 47);
+    }
+
+    #[test]
+    fn repeated_phrases() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let data = &text[..128 * 1024];
+        let encoded = encode(data, Settings::default(), 0).unwrap();
+        assert_eq!(decode(&encoded).unwrap(), data);
+        assert!(encoded.len() < data.len() * 45 / 100);
+
+        //more effort and a larger window do not get worse
+        let harder = encode(data, Settings { window: 128 * 1024, effort: 256 }, 0).unwrap();
+        assert!(harder.len() <= encoded.len());
+        assert_eq!(decode(&harder).unwrap(), data);
+    }
+
+    #[test]
+    fn overlapping_and_small() {
+        let runs = [b"a".repeat(1000), b"abc".repeat(300), Vec::new(), b"ab".to_vec()].concat();
+        for data in [&runs[..], b"", b"x", b"xyzxyz"] {
+            assert_eq!(decode(&encode(data, Settings::default(), 0).unwrap()).unwrap(), data);
+        }
+        assert!(encode(b"abc", Settings { effort: 0, ..Default::default() }, 0).is_err());
+        //the chains are no longer than the block, however large the window
+        let wide = Settings { window: MAX_WINDOW, ..Default::default() };
+        assert_eq!(Finder::new(wide, runs.len()).prev.len(), 2048);
+        assert_eq!(decode(&encode(&runs, wide, 0).unwrap()).unwrap(), runs);
+        let encoded = encode(&runs, Settings::default(), 0).unwrap();
+        assert!(decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+}
@@ -4,16 +4,32 @@ mod huffman;
 mod node;
 mod operations;
 mod interface;
+mod limit;
+mod bits;
+mod table;
+mod stream;
+mod adaptive;
+mod format;
+mod crc;
+mod archive;
+mod context;
+mod tokens;
+mod lz77;
+mod deflate;
+mod bwt;
+mod coder;
+mod dictionary;
+mod seek;
 
 use interface::Args;
 use operations::run;
 
 fn main() {
-    let args = Args::from_env_args().unwrap();
-    match run(args) {
+    //bad arguments and a dictionary that can not be read end like any other error
+    match Args::from_env_args().and_then(run) {
         Ok(_) => std::process::exit(0),
         Err(err) => {
-            eprintln!("{}", err.to_string());
+            eprintln!("{}", err);
             std::process::exit(1);
         } 
     };
@@ -1,12 +1,11 @@
-use crate::utils::read_u32;
 use crate::huffman::TravRes;
 use crate::error::{Result, Error};
 use std::cmp::Ordering;
 
 
-#[derive(Clone, Ord, Eq)]
+#[derive(Clone, Debug)]
 pub struct Node {
-    pub ch: char,
+    pub sym: u32,
     pub freq: u32,
     pub left: Option<Box<Node>>,
     pub right: Option<Box<Node>>,
@@ -15,38 +14,39 @@ pub struct Node {
 
 
 impl Node {
-    pub fn new_node(ch: char, freq: u32) -> Self {
+    pub fn new_node(sym: u32, freq: u32) -> Self {
         Self {
-            ch,
+            sym,
             freq,
             left: None,
             right: None,
         }
     }
-    pub fn from_flat(data: &[u8]) -> Result<Self> {
-        let ch = data[0] as char;
-        let freq = read_u32(&data[1..5])?;
-        Ok(Self::new_node(ch, freq))
-    }
-
     pub fn is_leave(&self) -> bool {
         self.left.is_none() && self.right.is_none()
     }
 
-    pub fn flat(&self) -> Option<Vec<u8>> {
-        if self.is_leave() {
-        let mut buf = Vec::with_capacity(5);
-        buf.push(self.ch as u8);
-        buf.extend_from_slice(&self.freq.to_be_bytes());
-        Some(buf)
-        } else {
-            None
+    /// length of the longest path from this node to a leaf
+    pub fn depth(&self) -> usize {
+        let left = self.left.as_ref().map(|n| n.depth() + 1).unwrap_or(0);
+        let right = self.right.as_ref().map(|n| n.depth() + 1).unwrap_or(0);
+        left.max(right)
+    }
+
+    /// places a leaf for `sym` at the end of the `len` bits long `code`, read from its most
+    /// significant bit (0 is left, 1 is right), creating the inner nodes on the way
+    pub fn insert_code(&mut self, sym: u32, code: u64, len: u8) {
+        let mut node = self;
+        for i in (0..len).rev() {
+            let child = if code & (1 << i) == 0 { &mut node.left } else { &mut node.right };
+            node = child.get_or_insert_with(|| Box::new(Node::new_node(0, 0)));
         }
+        node.sym = sym;
     }
 
-    pub fn next(&self, dir: u8) -> Result<TravRes> {
+    pub fn next(&self, dir: u8) -> Result<TravRes<'_>> {
         if self.is_leave() {
-            return Ok(TravRes::Char(self.ch))
+            return Ok(TravRes::Sym(self.sym))
         }
         if dir == 0 {
             if let Some(left) = self.left.as_ref() {
@@ -73,19 +73,7 @@ impl PartialEq for Node {
 
 impl PartialOrd for Node {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        if self < other {
-            Some(Ordering::Less)
-        } else if self > other {
-            Some(Ordering::Greater)
-        } else {
-            Some(Ordering::Equal)
-        }
-    }
-    fn lt(&self, other: &Self) -> bool {
-        self.freq < other.freq
-    }
-    fn gt(&self, other: &Self) -> bool {
-        self.freq > other.freq
+        Some(self.freq.cmp(&other.freq))
     }
 }
 
@@ -94,10 +82,10 @@ mod test {
     use super::*;
     #[test]
     fn ordering() {
-        let node1 = Node::new_node('a', 1);
-        let node2 = Node::new_node('b', 2);
-        let node3 = Node::new_node('d', 3);
-        let node4 = Node::new_node('d', 3);
+        let node1 = Node::new_node('a' as u32, 1);
+        let node2 = Node::new_node('b' as u32, 2);
+        let node3 = Node::new_node('d' as u32, 3);
+        let node4 = Node::new_node('d' as u32, 3);
         assert!(node1 != node2);
         assert!(node3 == node4);
         assert!(node1 < node2);
@@ -106,37 +94,43 @@ mod test {
 
 
     #[test]
-    fn flatten() {
-        let node = Node::new_node('a', 1);
-        let flattened = vec![97, 0, 0, 0, 1];
-        assert_eq!(node.flat(), Some(flattened.clone()));
-        let from = Node::from_flat(&flattened).unwrap();
-        assert_eq!(from.ch, 'a');
-        assert_eq!(from.freq, 1);
-
+    fn insert_codes() {
+        let mut root = Node::new_node(0, 0);
+        root.insert_code('a' as u32, 0b0, 1);
+        root.insert_code('b' as u32, 0b10, 2);
+        root.insert_code('c' as u32, 0b11, 2);
+        let left = root.left.as_ref().unwrap();
+        assert!(left.is_leave());
+        assert_eq!(left.sym, 'a' as u32);
+        let right = root.right.as_ref().unwrap();
+        assert!(!right.is_leave());
+        assert_eq!(right.left.as_ref().unwrap().sym, 'b' as u32);
+        assert_eq!(right.right.as_ref().unwrap().sym, 'c' as u32);
+        assert_eq!(root.depth(), 2);
+        assert_eq!(root.left.as_ref().unwrap().depth(), 0);
     }
 
 
     #[test]
     fn is_a_leave() {
-        let node = Node::new_node('a', 1);
+        let node = Node::new_node('a' as u32, 1);
         assert!(node.is_leave());
-        let mut node_non_leave = Node::new_node('b', 2);
+        let mut node_non_leave = Node::new_node('b' as u32, 2);
         node_non_leave.left = Some(Box::new(node));
         assert!(!node_non_leave.is_leave());
     }
 
     #[test]
     fn traverse() {
-        let mut node1 = Node::new_node('a', 1);
-        let mut node3 = Node::new_node('d', 3);
-        let mut node_a = Node::new_node('\0', 4);
+        let node1 = Node::new_node('a' as u32, 1);
+        let node3 = Node::new_node('d' as u32, 3);
+        let mut node_a = Node::new_node(0, 4);
         node_a.left = Some(Box::new(node1.clone()));
         node_a.right = Some(Box::new(node3.clone()));
 
         assert!(node_a.next(0).unwrap() == TravRes::Node(&Box::new(node1)));
         assert!(node_a.next(1).unwrap() == TravRes::Node(&Box::new(node3.clone())));
-        assert!(node3.next(0).unwrap()  == TravRes::Char('d'));
+        assert!(node3.next(0).unwrap()  == TravRes::Sym('d' as u32));
         assert!(node_a.next(2).is_err());
 
     }
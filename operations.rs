@@ -1,10 +1,15 @@
-use crate::interface::{Args, usage};
+use crate::interface::{Args, Command};
 use crate::error::{Error, Result};
-use crate::huffman::Huffman;
-use crate::utils::{in_file_to_out_file, out_file_to_in_file};
-use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
-use std::io::{Read, Write, self};
+use crate::format::{self, Header, FLAG_ADAPTIVE, FLAG_INDEX};
+use crate::huffman::Mode;
+use crate::archive::{self, Kind};
+use crate::deflate::{self, Container};
+use crate::dictionary::Dictionary;
+use crate::seek;
+use crate::utils::{append_extension, in_file_to_out_file, out_file_to_in_file, file_meta, restore_meta};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, BufWriter, Read, Write, self};
 
 enum Out {
     File(PathBuf),
@@ -12,63 +17,249 @@ enum Out {
 }
 
 
-pub fn run(args: Args) -> Result<()> {
-    let out_path: Out;
-    let mut out_data: Vec<u8> = Vec::new();
-    if let Some(in_file) = args.in_file {
-        let mut file = File::open(in_file.as_str()).map_err(|err| Error::Compress(format!("could not open file '{}'", in_file), err.to_string()))?;
-        if args.decompress {
-            //it's a compression
-            let mut buf = Vec::new();
-            file.read_to_end(&mut buf).map_err(|err| Error::DeCompress(format!("could not read file '{}'", in_file), err.to_string()))?;
-            let mut huf = Huffman::from_bytes(&buf);
-            let _ = huf.decode()?;
-            out_path = if args.print_out {
-                Out::StdOut
-            } else {Out::File(out_file_to_in_file(PathBuf::from(in_file)))
-        }; 
-            huf.data_to_bytes(&mut out_data);
-        } else {
-            //it's a compression from file
-            let mut buf = String::new();
-            let mut file = File::open(in_file.as_str()).map_err(|err| Error::Compress(format!("could not open file '{}'", in_file), err.to_string()))?;
-        file.read_to_string(&mut buf).map_err(|err| Error::Compress(format!("could not read file '{}'", in_file), err.to_string()))?;
-            let mut huffman = Huffman::from_str(buf.as_str());
-            huffman.create_tree();
-            huffman.codes();
-            let _ = huffman.encode()?;
-            out_path = Out::File(in_file_to_out_file(PathBuf::from(in_file)));
-            huffman.read_bytes_into(&mut out_data);
-        }
-    } else if let Some(data) = args.data {
-        //can only be a compression
-        let mut huffman = Huffman::from_str(data.as_str());
-        huffman.create_tree();
-        huffman.codes();
-        let _ = huffman.encode()?;
-        huffman.data_to_bytes(&mut out_data);
-        if args.print_out {
-            out_path = Out::StdOut;
-        } else {
-            out_path = Out::File(PathBuf::from("out.huf"));
-        }
-        huffman.data_to_bytes(&mut out_data);
-    } else {
-        usage(args.program.as_str());
-        return Err(Error::Args("Insufficient args!".to_string(), "".to_string()))
+pub fn run(mut args: Args) -> Result<()> {
+    if args.command == Some(Command::Train) {
+        return train(&args);
+    }
+    //a dictionary is meant for many small files, archives already share their tables
+    if args.dictionary.is_some() && (args.command.is_some() || args.archive.is_some() || args.in_file.as_ref().is_some_and(|in_file| Path::new(in_file.as_str()).is_dir())) {
+        return Err(Error::Args("'--dict' does not work with archives".to_string(), String::new()));
+    }
+    if let Some(command) = args.command {
+        return run_command(&args, command);
+    }
+    if let Some((from, to)) = args.range {
+        return print_range(&args, from, to);
+    }
+    if args.index && args.adaptive {
+        return Err(Error::Args("'--index' needs blocks, it does not work with '--adaptive'".to_string(), String::new()));
+    }
+    if let Some(dictionary) = args.dictionary.as_ref() {
+        args.text = dictionary.mode == Mode::Text;
     }
+    if let Some(archive) = args.archive.as_ref() {
+        let inputs = args.in_file.iter().chain(args.paths.iter()).map(PathBuf::from).collect::<Vec<PathBuf>>();
+        return archive::create(Path::new(archive.as_str()), &inputs, &args.options(), compress_header(&args, None).flags, args.solid).map(|_| ());
+    }
+    if let Some(in_file) = args.in_file.as_ref().filter(|in_file| Path::new(in_file.as_str()).is_dir()) {
+        let out = in_file_to_out_file(PathBuf::from(in_file.trim_end_matches('/')));
+        return archive::create(out.as_path(), &[PathBuf::from(in_file)], &args.options(), compress_header(&args, None).flags, args.solid).map(|_| ());
+    }
+    let mut input: Box<dyn Read> = match args.in_file.as_ref() {
+        Some(in_file) => {
+            let file = File::open(in_file.as_str()).map_err(|err| Error::Compress(format!("could not open file '{}'", in_file), err.to_string()))?;
+            Box::new(BufReader::new(file))
+        },
+        None => Box::new(io::stdin().lock()),
+    };
+    //compressed input is recognized by its magic bytes, whatever the file is called
+    let mut magic = Vec::with_capacity(format::MAGIC.len());
+    input.by_ref().take(format::MAGIC.len() as u64).read_to_end(&mut magic).map_err(|err| Error::Compress("could not read input".to_string(), err.to_string()))?;
+    if archive::is_archive(&magic) && !args.compress {
+        return extract(&args);
+    }
+    if !args.paths.is_empty() {
+        return Err(Error::Args("more than one input needs '--archive'".to_string(), args.paths.join(" ")));
+    }
+    //gzip input is recognized too, zlib and raw DEFLATE streams have no magic to go by
+    let gzip_input = !args.compress && deflate::is_gzip(&magic) && args.container.is_none_or(|container| container == Container::Gzip);
+    if gzip_input {
+        args.container = Some(Container::Gzip);
+    }
+    let decompress = args.decompress || gzip_input || (!args.compress && args.container.is_none() && format::is_compressed(&magic));
+    let mut input = magic.as_slice().chain(input);
+    let header = if decompress && args.container.is_some() {
+        //gzip does not restore the stored name and mtime either
+        Header::new(Mode::Bytes, None)
+    } else if decompress {
+        Header::read(&mut input)?
+    } else {
+        compress_header(&args, args.in_file.as_deref())
+    };
+
+    let out_path = match args.in_file.as_ref() {
+        _ if args.print_out => Out::StdOut,
+        Some(in_file) if decompress => {
+            let name = header.name.as_deref().filter(|_| args.keep_name);
+            Out::File(out_file_to_in_file(PathBuf::from(in_file), name))
+        },
+        Some(in_file) => match args.container {
+            Some(container) => Out::File(append_extension(PathBuf::from(in_file), container.extension())),
+            None => Out::File(in_file_to_out_file(PathBuf::from(in_file))),
+        },
+        //decompressed data from a pipe goes back into the pipe
+        None if decompress => Out::StdOut,
+        None => Out::File(PathBuf::from(format!("out.{}", args.container.map(|container| container.extension()).unwrap_or("huf")))),
+    };
+
     //write file or print
     match out_path {
         Out::File(path) => {
-            let mut out = OpenOptions::new().create(true).write(true).open(path.as_path()).map_err(|err| Error::Finalizing("Could not create out file".to_string(), err.to_string()))?;
-
-                out.write_all(out_data.as_slice()).map_err(|err| Error::Finalizing("Could not write to stdout".to_string(), err.to_string()))?;
+            let out = OpenOptions::new().create(true).write(true).truncate(true).open(path.as_path()).map_err(|err| Error::Finalizing("Could not create out file".to_string(), err.to_string()))?;
+            let mut result = transform(&args, &header, decompress, &mut input, &mut BufWriter::new(out));
+            if let (Ok(_), Some(meta)) = (result.as_ref(), header.meta) {
+                if decompress && args.keep_name {
+                    result = restore_meta(path.as_path(), meta);
+                }
+            }
+            if result.is_err() {
+                //do not leave half written files behind
+                let _ = fs::remove_file(path.as_path());
+            }
+            result
         },
         Out::StdOut => {
             let mut handle = io::stdout().lock();
+            transform(&args, &header, decompress, &mut input, &mut handle)
+        }
+    }
+}
+
+/// the header for compressing `in_file` with `args`, size, name and attributes are only known for files
+fn compress_header(args: &Args, in_file: Option<&str>) -> Header {
+    let mode = if args.text && !args.adaptive { Mode::Text } else { Mode::Bytes };
+    let mut header = Header::new(mode, None);
+    if args.adaptive {
+        header.flags |= FLAG_ADAPTIVE;
+    } else if args.index {
+        header.flags |= FLAG_INDEX;
+    }
+    let path = match in_file {
+        Some(in_file) => Path::new(in_file),
+        None => return header,
+    };
+    if let Ok(meta) = fs::metadata(path) {
+        header = header.with_size(meta.len());
+    }
+    if args.keep_name {
+        if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+            header = header.with_name(name);
+        }
+        if let Some(meta) = file_meta(path) {
+            header = header.with_meta(meta);
+        }
+    }
+    header
+}
+
+/// prints the original bytes `from..to` of `args.in_file`, see `seek::decompress_range`
+fn print_range(args: &Args, from: u64, to: u64) -> Result<()> {
+    let in_file = match args.in_file.as_ref() {
+        Some(in_file) => in_file,
+        None => return Err(Error::Args("'--range' needs a file to seek in".to_string(), String::new())),
+    };
+    let file = File::open(in_file.as_str()).map_err(|err| Error::DeCompress(format!("could not open file '{}'", in_file), err.to_string()))?;
+    let mut handle = io::stdout().lock();
+    seek::decompress_range(&mut BufReader::new(file), &mut handle, from, to, args.decoder, args.dictionary.as_deref())?;
+    Ok(())
+}
+
+/// builds a dictionary from the files and directories `args.paths` and writes it to `args.in_file`
+fn train(args: &Args) -> Result<()> {
+    let out = match args.in_file.as_ref() {
+        Some(out) => Path::new(out.as_str()),
+        None => return Err(Error::Args("'train' needs a dictionary file to write".to_string(), String::new())),
+    };
+    let mut sources = Vec::new();
+    for path in args.paths.iter() {
+        archive::collect(Path::new(path.as_str()), path.clone(), &mut sources)?;
+    }
+    let mut samples = Vec::new();
+    for (source, _, kind) in sources {
+        if kind == Kind::File {
+            samples.push(fs::read(source.as_path()).map_err(|err| Error::Compress(format!("could not read '{}'", source.display()), err.to_string()))?);
+        }
+    }
+    if samples.is_empty() {
+        return Err(Error::Args("'train' needs sample files".to_string(), String::new()));
+    }
+    let mode = if args.text { Mode::Text } else { Mode::Bytes };
+    let dictionary = Dictionary::train(mode, &samples, args.max_len)?;
+    fs::write(out, dictionary.to_bytes()?).map_err(|err| Error::Finalizing("could not write dictionary".to_string(), err.to_string()))?;
+    println!("dictionary {:08x} from {} files with {} bytes", dictionary.id, samples.len(), samples.iter().map(|sample| sample.len()).sum::<usize>());
+    Ok(())
+}
 
-                handle.write_all(out_data.as_slice()).map_err(|err| Error::Finalizing("Could not write to stdout".to_string(), err.to_string()))?;
+/// runs an archive subcommand on the archive `args.in_file`
+fn run_command(args: &Args, command: Command) -> Result<()> {
+    let archive = match args.in_file.as_ref() {
+        Some(archive) => Path::new(archive.as_str()),
+        None => return Err(Error::Args("archive commands need an archive".to_string(), String::new())),
+    };
+    match command {
+        Command::List => {
+            println!("{:>12} {:>12} {:>6}  path", "size", "compressed", "ratio");
+            let entries = archive::list(archive)?;
+            for entry in entries.iter() {
+                match entry.kind {
+                    Kind::File => {
+                        //files of a solid group get their share of the group
+                        let group: u64 = entries.iter().filter(|other| other.kind == Kind::File && other.offset == entry.offset).map(|other| other.size).sum();
+                        let length = (entry.length as u128 * entry.size as u128).checked_div(group as u128).unwrap_or(entry.length as u128) as u64;
+                        let ratio = if entry.size == 0 { 0.0 } else { length as f64 * 100.0 / entry.size as f64 };
+                        println!("{:>12} {:>12} {:>5.1}%  {}", entry.size, length, ratio, entry.path);
+                    },
+                    Kind::Dir => println!("{:>12} {:>12} {:>6}  {}/", "-", "-", "", entry.path),
+                }
+            }
+        },
+        Command::Extract => extract(args)?,
+        Command::Add => {
+            let inputs = args.paths.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+            if inputs.is_empty() {
+                return Err(Error::Args("'add' needs files or directories to add".to_string(), String::new()));
+            }
+            archive::add(archive, &inputs, &args.options(), compress_header(args, None).flags, args.solid)?;
+        },
+        Command::Train => train(args)?,
+        Command::Delete => {
+            for entry in archive::delete(archive, &args.paths, &args.options(), args.decoder)? {
+                println!("deleted {}", entry.path);
+            }
+        },
+    }
+    Ok(())
+}
+
+/// extracts the archive `args.in_file`, or prints the selected members
+fn extract(args: &Args) -> Result<()> {
+    let in_file = match args.in_file.as_ref() {
+        Some(in_file) => Path::new(in_file.as_str()),
+        None => return Err(Error::Args("archives can only be extracted from a file".to_string(), String::new())),
+    };
+    if args.print_out {
+        let mut reader = BufReader::new(File::open(in_file).map_err(|err| Error::DeCompress("could not open archive".to_string(), err.to_string()))?);
+        let entries = archive::read_index(&mut reader)?;
+        let mut handle = io::stdout().lock();
+        for entry in archive::select(&entries, &args.paths)? {
+            if entry.kind == Kind::File {
+                archive::extract_member(&mut reader, entry, &mut handle, args.decoder)?;
+            }
         }
+        return Ok(());
+    }
+    let dest = args.directory.as_deref().unwrap_or(".");
+    archive::extract(in_file, Path::new(dest), &args.paths, args.decoder).map(|_| ())
+}
+
+/// compresses `input` with `header` in front, or decompresses `input` whose `header` was already read.
+/// With a container set the data is DEFLATE instead, and only name and mtime of `header` are used
+fn transform<R: Read, W: Write>(args: &Args, header: &Header, decompress: bool, input: &mut R, output: &mut W) -> Result<()> {
+    if let Some(container) = args.container {
+        let settings = args.lz.unwrap_or_default();
+        let mtime = header.meta.map(|meta| meta.mtime.min(u32::MAX as u64) as u32).unwrap_or(0);
+        match (container, decompress) {
+            (Container::Gzip, false) => deflate::gzip(input, output, header.name.as_deref(), mtime, settings)?,
+            (Container::Gzip, true) => deflate::gunzip(input, output)?.0,
+            (Container::Zlib, false) => deflate::zlib(input, output, settings)?,
+            (Container::Zlib, true) => deflate::unzlib(input, output)?,
+            (Container::Raw, false) => deflate::compress_raw(input, output, settings)?,
+            (Container::Raw, true) => deflate::decompress_raw(input, output)?,
+        };
+    } else if decompress {
+        format::decompress_body(input, output, header, args.decoder, args.dictionary.as_deref(), args.threads)?;
+    } else {
+        format::compress(input, output, header, &args.options())?;
     }
     Ok(())
 }
@@ -76,6 +267,7 @@ pub fn run(args: Args) -> Result<()> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::huffman::Decoder;
 
     //NOTE these tests do nothing
     #[test]
@@ -84,10 +276,13 @@ mod test {
             program: String::new(),
             in_file: Some(String::from("test_input.txt")),
             decompress: false,
-            data: None,
-            print_out: false 
+            print_out: false,
+            text: false,
+            max_len: 0,
+            decoder: Decoder::Table,
+            ..Default::default()
         };
-        run(args);
+        let _ = run(args);
     }
 
    #[test]
@@ -97,11 +292,60 @@ mod test {
            program: String::new(),
            in_file: Some(String::from("test_output.huf")),
            decompress: true,
-           data: None,
-           print_out: true 
+           print_out: true,
+           text: false,
+           max_len: 0,
+           decoder: Decoder::Tree,
+           ..Default::default()
        };
-       run(args);
+       let _ = run(args);
    }
-}
 
+    /// compresses `data` in memory like `run` does for a file
+    fn compress(data: &[u8], args: &Args) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let header = Header::new(Mode::Bytes, Some(data.len() as u64)).with_name("data.bin");
+        transform(args, &header, false, &mut &data[..], &mut compressed).unwrap();
+        compressed
+    }
+
+    /// reads the header and decompresses the rest
+    fn decompress(mut compressed: &[u8], args: &Args, out: &mut Vec<u8>) -> Result<()> {
+        let header = Header::read(&mut compressed)?;
+        transform(args, &header, true, &mut compressed, out)
+    }
+
+    #[test]
+    fn flipped_bits_never_give_wrong_data() {
+        let data = b"flipped bits must never pass unnoticed";
+        for args in [Args::default(), Args { adaptive: true, ..Default::default() }] {
+            let compressed = compress(data, &args);
+            let mut out = Vec::new();
+            decompress(&compressed, &args, &mut out).unwrap();
+            assert_eq!(out, data);
+            for bit in 0..compressed.len() * 8 {
+                let mut flipped = compressed.clone();
+                flipped[bit / 8] ^= 1 << (bit % 8);
+                out.clear();
+                //a few bits do not change the result, like the mode of an ascii only block
+                if decompress(&flipped, &args, &mut out).is_ok() {
+                    assert_eq!(out, data, "flipped bit {} gave wrong data", bit);
+                }
+            }
+        }
+    }
 
+    #[test]
+    fn integrity_error() {
+        let data = b"aaaaaaaabbbbccd";
+        let mut compressed = compress(data, &Args::default());
+        //the data decodes fine, but does not match the stored checksum anymore
+        let last = compressed.len() - 1;
+        compressed[last] ^= 0xff;
+        let mut out = Vec::new();
+        match decompress(&compressed, &Args::default(), &mut out) {
+            Err(Error::Integrity(_, _)) => {},
+            other => panic!("expected an integrity error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use crate::error::{Result, Error};
+use crate::format::{self, Header, FLAG_INDEX};
+use crate::crc::crc32;
+use crate::huffman::Decoder;
+use crate::dictionary::Dictionary;
+use crate::stream;
+use std::convert::TryInto;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// last bytes of every block index
+const INDEX_MAGIC: [u8; 4] = [b'I', b'U', b'H', 0x89];
+/// original size, number of blocks, checksum and magic
+const TRAILER_LEN: usize = 20;
+/// offset, start and checksum of a block
+const BLOCK_LEN: usize = 20;
+
+/// where a block starts in the body and in the original data
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Block {
+    /// position of the block frame, counted from the end of the header
+    pub offset: u64,
+    /// position of the first original byte of the block
+    pub start: u64,
+    /// CRC-32 of the original bytes of the block
+    pub crc: u32,
+}
+
+/// The blocks of a file written with `FLAG_INDEX`, it follows the checksum of the data:
+/// 8 bytes offset, 8 bytes start and 4 bytes checksum of every block, then the original size
+/// as 8 bytes, the number of blocks as 4 bytes, the CRC-32 of all of these as 4 bytes, all big
+/// endian, and 4 bytes magic. The fixed length trailer lets a reader find it from the end.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Index {
+    pub blocks: Vec<Block>,
+    /// length of the original data
+    pub size: u64,
+}
+
+impl Index {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.blocks.len() * BLOCK_LEN + TRAILER_LEN);
+        for block in self.blocks.iter() {
+            bytes.extend_from_slice(&block.offset.to_be_bytes());
+            bytes.extend_from_slice(&block.start.to_be_bytes());
+            bytes.extend_from_slice(&block.crc.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&(self.blocks.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&crc32(&bytes).to_be_bytes());
+        bytes.extend_from_slice(&INDEX_MAGIC);
+        bytes
+    }
+
+    /// reads and validates an index written by `to_bytes`, `bytes` must hold nothing else
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < TRAILER_LEN || bytes[bytes.len() - 4..] != INDEX_MAGIC {
+            return Err(Error::DeCompress("block index is missing".to_string(), format!("{} bytes after the data", bytes.len())));
+        }
+        let (body, trailer) = bytes.split_at(bytes.len() - 8);
+        let stored = u32::from_be_bytes(trailer[..4].try_into().unwrap_or_default());
+        if crc32(body) != stored {
+            return Err(Error::Integrity("block index checksum mismatch".to_string(), format!("stored {:#010x}, computed {:#010x}", stored, crc32(body))));
+        }
+        let (entries, fields) = body.split_at(body.len() - 12);
+        let size = u64::from_be_bytes(fields[..8].try_into().unwrap_or_default());
+        let count = u32::from_be_bytes(fields[8..].try_into().unwrap_or_default()) as usize;
+        if entries.len() != count * BLOCK_LEN {
+            return Err(Error::DeCompress("block index has the wrong length".to_string(), format!("{} blocks in {} bytes", count, entries.len())));
+        }
+        let mut blocks: Vec<Block> = Vec::with_capacity(count);
+        for entry in entries.chunks(BLOCK_LEN) {
+            let block = Block {
+                offset: u64::from_be_bytes(entry[..8].try_into().unwrap_or_default()),
+                start: u64::from_be_bytes(entry[8..16].try_into().unwrap_or_default()),
+                crc: u32::from_be_bytes(entry[16..].try_into().unwrap_or_default()),
+            };
+            //blocks are never empty, so both positions grow from block to block
+            let follows = match blocks.last() {
+                Some(last) => block.offset > last.offset && block.start > last.start,
+                None => block.offset == 0 && block.start == 0,
+            };
+            if !follows || block.start >= size {
+                return Err(Error::DeCompress("block index is out of order".to_string(), format!("block {}", blocks.len())));
+            }
+            blocks.push(block);
+        }
+        if blocks.is_empty() && size > 0 {
+            return Err(Error::DeCompress("block index has no blocks".to_string(), format!("{size} bytes")));
+        }
+        Ok(Self { blocks, size })
+    }
+
+    /// reads the index at the end of `input`, found by its trailer
+    pub fn read<R: Read + Seek>(input: &mut R) -> Result<Self> {
+        let end = input.seek(SeekFrom::End(0)).map_err(|err| Error::DeCompress("could not read block index".to_string(), err.to_string()))?;
+        let mut trailer = [0u8; TRAILER_LEN];
+        input.seek(SeekFrom::Start(end.saturating_sub(TRAILER_LEN as u64)))
+            .and_then(|_| input.read_exact(&mut trailer))
+            .map_err(|err| Error::DeCompress("could not read block index".to_string(), err.to_string()))?;
+        if trailer[16..] != INDEX_MAGIC {
+            return Err(Error::DeCompress("block index is missing".to_string(), String::new()));
+        }
+        let count = u32::from_be_bytes(trailer[8..12].try_into().unwrap_or_default()) as u64;
+        let len = count * BLOCK_LEN as u64 + TRAILER_LEN as u64;
+        if len > end {
+            return Err(Error::DeCompress("block index is longer than the file".to_string(), format!("{count} blocks")));
+        }
+        let mut bytes = Vec::new();
+        input.seek(SeekFrom::Start(end - len))
+            .and_then(|_| input.take(len).read_to_end(&mut bytes))
+            .map_err(|err| Error::DeCompress("could not read block index".to_string(), err.to_string()))?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// the block that holds the original byte at `pos`
+    fn block_of(&self, pos: u64) -> usize {
+        self.blocks.partition_point(|block| block.start <= pos).saturating_sub(1)
+    }
+
+    /// where the block after `idx` starts in the original data
+    fn end_of(&self, idx: usize) -> u64 {
+        self.blocks.get(idx + 1).map(|block| block.start).unwrap_or(self.size)
+    }
+}
+
+/// Decompresses the original bytes from `from` up to `to` (exclusive, cut at the end of the data)
+/// of a file written with `FLAG_INDEX`. Only the blocks that hold them are read, found by seeking
+/// to the index and from there to the first of them. Every block is checked against its checksum,
+/// the checksum of the whole data is not. Returns the number of bytes written.
+pub fn decompress_range<R: Read + Seek, W: Write>(input: &mut R, output: &mut W, from: u64, to: u64, decoder: Decoder, dictionary: Option<&Dictionary>) -> Result<u64> {
+    input.seek(SeekFrom::Start(0)).map_err(|err| Error::DeCompress("could not read input".to_string(), err.to_string()))?;
+    let header = Header::read(input)?;
+    if !header.has(FLAG_INDEX) {
+        return Err(Error::DeCompress("file has no block index, compress it with '--index'".to_string(), String::new()));
+    }
+    format::check_dictionary(&header, dictionary)?;
+    let body = input.stream_position().map_err(|err| Error::DeCompress("could not read input".to_string(), err.to_string()))?;
+    let index = Index::read(input)?;
+    let to = to.min(index.size);
+    if from >= to {
+        return Ok(0);
+    }
+    let first = index.block_of(from);
+    input.seek(SeekFrom::Start(body + index.blocks[first].offset)).map_err(|err| Error::DeCompress("could not seek to the block".to_string(), err.to_string()))?;
+    let mut written = 0;
+    for (idx, block) in index.blocks.iter().enumerate().skip(first).take_while(|(_, block)| block.start < to) {
+        let data = stream::decompress_block(input, decoder, header.coder, dictionary)?
+            .ok_or_else(|| Error::DeCompress("stream ends before the indexed blocks".to_string(), format!("block {idx}")))?;
+        if data.len() as u64 != index.end_of(idx) - block.start || crc32(&data) != block.crc {
+            return Err(Error::Integrity("block does not match the index".to_string(), format!("block {idx}")));
+        }
+        let skip = from.saturating_sub(block.start) as usize;
+        let stop = (to - block.start).min(data.len() as u64) as usize;
+        output.write_all(&data[skip..stop]).map_err(|err| Error::Finalizing("could not write output".to_string(), err.to_string()))?;
+        written += (stop - skip) as u64;
+    }
+    output.flush().map_err(|err| Error::Finalizing("could not flush output".to_string(), err.to_string()))?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::Header;
+    use crate::huffman::Mode;
+    use crate::stream::Options;
+    use std::io::Cursor;
+
+    /// `data` compressed with a block index and `options`
+    fn indexed(data: &[u8], options: &Options) -> Vec<u8> {
+        let mut header = Header::new(Mode::Bytes, Some(data.len() as u64));
+        header.flags |= FLAG_INDEX;
+        let mut compressed = Vec::new();
+        format::compress(&mut &data[..], &mut compressed, &header, options).unwrap();
+        compressed
+    }
+
+    fn range(compressed: &[u8], from: u64, to: u64) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        decompress_range(&mut Cursor::new(compressed), &mut out, from, to, Decoder::Table, None)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn ranges() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let options = Options { block_size: 8 * 1024, ..Default::default() };
+        let compressed = indexed(&text, &options);
+        let len = text.len() as u64;
+        for (from, to) in [(0, 10), (8190, 8200), (100_000, 130_000), (len - 5, len + 100), (0, len), (50, 50), (len, len + 1)] {
+            assert_eq!(range(&compressed, from, to).unwrap(), text[from.min(len) as usize..to.min(len) as usize]);
+        }
+
+        //the whole file still decompresses the usual way, the index is checked on the way
+        let mut input = compressed.as_slice();
+        let header = Header::read(&mut input).unwrap();
+        let mut out = Vec::new();
+        format::decompress_body(&mut input, &mut out, &header, Decoder::Table, None, 4).unwrap();
+        assert_eq!(out, text);
+
+        let options = Options { bwt: true, lz: Some(Default::default()), threads: 3, ..options };
+        let compressed = indexed(&text[..100_000], &options);
+        assert_eq!(range(&compressed, 20_000, 45_000).unwrap(), text[20_000..45_000]);
+        assert!(indexed(&[], &options).len() < 64);
+        assert_eq!(range(&indexed(&[], &options), 0, 10).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn damaged_index() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let compressed = indexed(&text[..40_000], &Options { block_size: 4096, ..Default::default() });
+        //files without an index can not be read in ranges
+        let mut plain = Vec::new();
+        format::compress(&mut &text[..100], &mut plain, &Header::new(Mode::Bytes, None), &Options::default()).unwrap();
+        assert!(range(&plain, 0, 10).is_err());
+
+        let end = compressed.len();
+        for pos in [end - 1, end - 8, end - 30] {
+            let mut corrupt = compressed.clone();
+            corrupt[pos] ^= 0x10;
+            assert!(range(&corrupt, 0, 10).is_err());
+        }
+        //a damaged block is found by its checksum, blocks before it still read
+        let mut corrupt = compressed.clone();
+        corrupt[end / 2] ^= 0x01;
+        assert!(range(&corrupt, 0, 40_000).is_err());
+        assert_eq!(range(&corrupt, 0, 100).unwrap(), text[..100]);
+
+        let index = Index { blocks: vec![Block { offset: 0, start: 0, crc: 1 }, Block { offset: 9, start: 0, crc: 2 }], size: 10 };
+        assert!(Index::from_bytes(&index.to_bytes()).is_err());
+        let index = Index { blocks: vec![Block { offset: 0, start: 0, crc: 1 }, Block { offset: 9, start: 5, crc: 2 }], size: 10 };
+        assert_eq!(Index::from_bytes(&index.to_bytes()).unwrap(), index);
+    }
+}
@@ -0,0 +1,640 @@
+use crate::error::{Result, Error};
+use crate::huffman::{Decoder, Huffman, Mode};
+use crate::context;
+use crate::tokens::{self, Alphabet};
+use crate::lz77;
+use crate::bwt;
+use crate::coder::Coder;
+use crate::dictionary::Dictionary;
+use crate::seek::{Block, Index};
+use crate::crc::crc32;
+use crate::utils::read_u32;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+/// default number of input bytes coded with one table, bounds the memory used by `compress`
+pub const BLOCK_SIZE: usize = 256 * 1024;
+/// largest allowed block, the coded block has to fit into the 4 byte frame length
+pub const MAX_BLOCK_SIZE: usize = 256 * 1024 * 1024;
+
+/// code lengths followed by the huffman stream, see `Huffman::encode_with_table`
+const BLOCK_TABLE: u8 = 0;
+/// the huffman stream alone, coded with the table of the last `BLOCK_TABLE` block
+/// where that is not larger than a table of its own
+const BLOCK_REUSE: u8 = 1;
+/// the input bytes as they are where coding would make them larger, so a block never
+/// takes more than 5 bytes over its input
+const BLOCK_STORED: u8 = 2;
+/// order-1 context tables (`Options::context`), see `context::encode`
+const BLOCK_CONTEXT: u8 = 3;
+/// words or n-grams and their dictionary (`Options::tokens`), see `tokens::encode`
+const BLOCK_TOKENS: u8 = 4;
+/// LZ77 with one table for literals and lengths and one for distances (`Options::lz`), see `lz77::encode`
+const BLOCK_LZ: u8 = 5;
+/// block sorted and move-to-front coded (`Options::bwt`), see `bwt::encode`
+const BLOCK_BWT: u8 = 6;
+/// 1 byte symbol mode and the symbols as the entropy coder of the file writes them,
+/// takes the place of `BLOCK_TABLE` for any coder but huffman
+const BLOCK_CODED: u8 = 7;
+/// the codes of a trained dictionary and no table (`Options::dictionary`), see `Dictionary::encode`
+const BLOCK_DICT: u8 = 8;
+
+/// settings of `compress`
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// code utf-8 characters instead of bytes
+    pub text: bool,
+    /// longest allowed code in bits, 0 for no limit
+    pub max_len: u8,
+    /// number of input bytes per block
+    pub block_size: usize,
+    /// code a block with the previous table if that is not larger than storing a new one
+    pub reuse_tables: bool,
+    /// also try order-1 context coding and keep it where it is smaller
+    pub context: bool,
+    /// also try coding words or n-grams as one symbol and keep it where it is smaller
+    pub tokens: Option<Alphabet>,
+    /// also try LZ77 with these settings and keep it where it is smaller
+    pub lz: Option<lz77::Settings>,
+    /// also try the Burrows-Wheeler transform and keep it where it is smaller
+    pub bwt: bool,
+    /// how symbols are turned into bits, anything but huffman codes every block with `BLOCK_CODED`
+    pub coder: Coder,
+    /// also try the codes of this dictionary and keep them where they are smaller
+    pub dictionary: Option<Arc<Dictionary>>,
+    /// number of blocks coded at once, each on its own thread
+    pub threads: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            text: false,
+            max_len: 0,
+            block_size: BLOCK_SIZE,
+            reuse_tables: true,
+            context: false,
+            tokens: None,
+            lz: None,
+            bwt: false,
+            coder: Coder::Huffman,
+            dictionary: None,
+            threads: 1,
+        }
+    }
+}
+
+/// Compresses `input` into `output` block by block, each written as 4 bytes length, 1 byte
+/// block type (see the `BLOCK_*` constants) and the coded block, a length of 0 ends the stream.
+/// Every block is coded in each way `options` allows and the smallest result is kept.
+/// With more than one of `threads` that many blocks are coded at once, and only the first of
+/// them may reuse the last table. Returns the number of bytes read.
+pub fn compress<R: Read, W: Write>(input: &mut R, output: &mut W, options: &Options) -> Result<u64> {
+    compress_blocks(input, output, options, None)
+}
+
+/// Compresses like `compress`, but every block carries its own table, so each one can be
+/// decoded on its own (see `decompress_block`). Returns where the blocks start.
+pub fn compress_indexed<R: Read, W: Write>(input: &mut R, output: &mut W, options: &Options) -> Result<Index> {
+    let options = Options { reuse_tables: false, ..options.clone() };
+    let mut index = Index::default();
+    index.size = compress_blocks(input, output, &options, Some(&mut index))?;
+    Ok(index)
+}
+
+/// see `compress`, the blocks are recorded in `index` if there is one
+fn compress_blocks<R: Read, W: Write>(input: &mut R, output: &mut W, options: &Options, mut index: Option<&mut Index>) -> Result<u64> {
+    if options.block_size == 0 || options.block_size > MAX_BLOCK_SIZE {
+        return Err(Error::Compress(format!("block size has to be between 1 and {} bytes", MAX_BLOCK_SIZE), format!("{}", options.block_size)));
+    }
+    let mut carry = Vec::new();
+    let mut previous: Option<Huffman> = None;
+    let mut total = 0u64;
+    let (mut written, mut start) = (0u64, 0u64);
+    let mut at_end = false;
+    while !at_end {
+        let mut blocks = Vec::new();
+        while blocks.len() < options.threads.max(1) {
+            let mut block = std::mem::take(&mut carry);
+            //a block smaller than a char grows until the char is complete
+            let size = options.block_size.max(block.len() + 1);
+            let got = read_block(input, &mut block, size)?;
+            total += got as u64;
+            if block.is_empty() {
+                at_end = true;
+                break;
+            }
+            if options.text {
+                //a char split by the block boundary moves on to the next block
+                let valid = match std::str::from_utf8(&block) {
+                    Ok(_) => block.len(),
+                    Err(err) if err.error_len().is_none() && got > 0 => err.valid_up_to(),
+                    Err(err) => return Err(Error::Compress("text mode requires utf-8 input".to_string(), err.to_string())),
+                };
+                carry.extend_from_slice(&block[valid..]);
+                block.truncate(valid);
+                if block.is_empty() {
+                    continue;
+                }
+            }
+            blocks.push(block);
+        }
+        //the first block of a round may reuse the last table, the others can not know it
+        let encoded = match blocks.len() {
+            1 => vec![encode_block(&blocks[0], options, previous.as_ref())?],
+            _ => in_parallel(blocks.iter().enumerate().collect(), |(idx, block)| {
+                encode_block(block, options, previous.as_ref().filter(|_| idx == 0))
+            })?,
+        };
+        for (block, (encoded, huffman)) in blocks.iter().zip(encoded) {
+            if let Some(index) = index.as_mut() {
+                index.blocks.push(Block { offset: written, start, crc: crc32(block) });
+            }
+            written += 4 + encoded.len() as u64;
+            start += block.len() as u64;
+            write_frame(output, &encoded)?;
+            //the decoder only remembers tables of `BLOCK_TABLE` blocks
+            if encoded[0] == BLOCK_TABLE {
+                previous = Some(huffman);
+            }
+        }
+    }
+    write_frame(output, &[])?;
+    output.flush().map_err(|err| Error::Finalizing("could not flush output".to_string(), err.to_string()))?;
+    Ok(total)
+}
+
+/// the smallest frame for `block` and its order-0 table, see `compress`
+fn encode_block(block: &[u8], options: &Options, previous: Option<&Huffman>) -> Result<(Vec<u8>, Huffman)> {
+    let coder = options.coder.build(options.max_len, Decoder::default());
+    let mut huffman = if options.text {
+        Huffman::from_str(std::str::from_utf8(block).map_err(|err| Error::Compress("text mode requires utf-8 input".to_string(), err.to_string()))?)
+    } else {
+        Huffman::from_raw(block)
+    };
+    huffman.set_max_len(options.max_len)?;
+    huffman.create_tree()?;
+    huffman.codes();
+
+    let reuse = match previous {
+        Some(previous) if options.reuse_tables => {
+            let fresh = huffman.table_bits()? + huffman.cost_with(&huffman).unwrap_or(u64::MAX);
+            huffman.cost_with(previous).map(|cost| cost <= fresh).unwrap_or(false)
+        },
+        _ => false,
+    };
+    let mode = if options.text { Mode::Text } else { Mode::Bytes };
+    let mut encoded = Vec::new();
+    if options.coder != Coder::Huffman {
+        encoded.extend_from_slice(&[BLOCK_CODED, mode.to_byte()]);
+        encoded.extend_from_slice(&coder.encode(&mode.symbols(block)?.collect::<Vec<u32>>())?);
+    } else if reuse {
+        if let Some(previous) = previous {
+            huffman.reuse_codes(previous);
+        }
+        huffman.encode_with_table(false)?;
+        encoded.push(BLOCK_REUSE);
+        huffman.read_bytes_into(&mut encoded);
+    } else {
+        huffman.encode()?;
+        encoded.push(BLOCK_TABLE);
+        huffman.read_bytes_into(&mut encoded);
+    }
+    if let Some(dictionary) = options.dictionary.as_ref() {
+        keep_smaller(&mut encoded, BLOCK_DICT, dictionary.encode(block)?);
+    }
+    if options.context {
+        let symbols = mode.symbols(block)?.collect::<Vec<u32>>();
+        keep_smaller(&mut encoded, BLOCK_CONTEXT, context::encode(mode, &symbols, options.max_len)?);
+    }
+    if let Some(alphabet) = options.tokens {
+        keep_smaller(&mut encoded, BLOCK_TOKENS, tokens::encode(alphabet, block, options.max_len, coder.as_ref())?);
+    }
+    if let Some(settings) = options.lz {
+        keep_smaller(&mut encoded, BLOCK_LZ, lz77::encode(block, settings, options.max_len)?);
+    }
+    if options.bwt {
+        keep_smaller(&mut encoded, BLOCK_BWT, bwt::encode(block, coder.as_ref())?);
+    }
+    if encoded.len() > block.len() + 1 {
+        encoded.clear();
+        encoded.push(BLOCK_STORED);
+        encoded.extend_from_slice(block);
+    }
+    Ok((encoded, huffman))
+}
+
+/// a block to decode, the huffman ones already know their table
+enum Job<'f> {
+    Whole(&'f [u8]),
+    Table(Huffman),
+    Reuse(Huffman),
+}
+
+/// Decompresses a stream written by `compress` with `coder` and `dictionary` block by block.
+/// With more than one of `threads`, that many blocks are decoded at once, each on its own thread.
+/// Returns the number of bytes written.
+pub fn decompress<R: Read, W: Write>(input: &mut R, output: &mut W, decoder: Decoder, coder: Coder, dictionary: Option<&Dictionary>, threads: usize) -> Result<u64> {
+    let mut total = 0u64;
+    let mut previous: Option<Huffman> = None;
+    let mut at_end = false;
+    while !at_end {
+        let mut frames = Vec::new();
+        while frames.len() < threads.max(1) {
+            let mut frame = Vec::new();
+            if !read_frame(input, &mut frame)? {
+                at_end = true;
+                break;
+            }
+            frames.push(frame);
+        }
+        //tables are read in order, so a reused one is known before any block is decoded
+        let mut jobs: Vec<Job> = Vec::with_capacity(frames.len());
+        let mut last_table: Option<usize> = None;
+        for frame in frames.iter() {
+            let job = match frame[0] {
+                BLOCK_TABLE => Job::Table(Huffman::from_bytes(&frame[1..])?),
+                BLOCK_REUSE => {
+                    let table = match last_table.map(|idx| &jobs[idx]) {
+                        Some(Job::Table(table)) => Some(table),
+                        _ => previous.as_ref(),
+                    };
+                    match table {
+                        Some(table) => Job::Reuse(Huffman::from_bytes_reusing(&frame[1..], table)?),
+                        None => return Err(Error::DeCompress("block reuses a table, but none came before".to_string(), String::new())),
+                    }
+                },
+                _ => Job::Whole(frame),
+            };
+            if let Job::Table(_) = job {
+                last_table = Some(jobs.len());
+            }
+            jobs.push(job);
+        }
+        let decoded = match jobs.len() {
+            0 => Vec::new(),
+            1 => jobs.into_iter().map(|job| decode_block(job, decoder, coder, dictionary)).collect::<Result<Vec<_>>>()?,
+            _ => in_parallel(jobs, |job| decode_block(job, decoder, coder, dictionary))?,
+        };
+        for (data, table) in decoded {
+            output.write_all(&data).map_err(|err| Error::Finalizing("could not write output".to_string(), err.to_string()))?;
+            total += data.len() as u64;
+            if table.is_some() {
+                previous = table;
+            }
+        }
+    }
+    output.flush().map_err(|err| Error::Finalizing("could not flush output".to_string(), err.to_string()))?;
+    Ok(total)
+}
+
+/// Decodes the next block of a stream whose blocks carry their own tables, like the ones
+/// `compress_indexed` writes. Returns `None` at the end of the stream.
+pub fn decompress_block<R: Read>(input: &mut R, decoder: Decoder, coder: Coder, dictionary: Option<&Dictionary>) -> Result<Option<Vec<u8>>> {
+    let mut frame = Vec::new();
+    if !read_frame(input, &mut frame)? {
+        return Ok(None);
+    }
+    let job = match frame[0] {
+        BLOCK_TABLE => Job::Table(Huffman::from_bytes(&frame[1..])?),
+        BLOCK_REUSE => return Err(Error::DeCompress("block reuses the table of another block and can not be decoded on its own".to_string(), String::new())),
+        _ => Job::Whole(&frame),
+    };
+    decode_block(job, decoder, coder, dictionary).map(|(data, _)| Some(data))
+}
+
+/// the data of one block, and its table if the block carried one
+fn decode_block(job: Job, decoder: Decoder, coder: Coder, dictionary: Option<&Dictionary>) -> Result<(Vec<u8>, Option<Huffman>)> {
+    let (mut huffman, carried) = match job {
+        Job::Table(huffman) => (huffman, true),
+        Job::Reuse(huffman) => (huffman, false),
+        Job::Whole(frame) => return Ok((decode_whole(frame, decoder, coder, dictionary)?, None)),
+    };
+    huffman.set_decoder(decoder);
+    huffman.decode()?;
+    let mut data = Vec::new();
+    huffman.data_to_bytes(&mut data);
+    Ok((data, if carried { Some(huffman) } else { None }))
+}
+
+/// the data of a block that needs no table of another block
+fn decode_whole(frame: &[u8], decoder: Decoder, coder: Coder, dictionary: Option<&Dictionary>) -> Result<Vec<u8>> {
+    let coder = coder.build(0, decoder);
+    let data = match frame[0] {
+        BLOCK_STORED => frame[1..].to_vec(),
+        BLOCK_CONTEXT => context::decode(&frame[1..])?,
+        BLOCK_TOKENS => tokens::decode(&frame[1..], coder.as_ref())?,
+        BLOCK_LZ => lz77::decode(&frame[1..])?,
+        BLOCK_BWT => bwt::decode(&frame[1..], coder.as_ref())?,
+        BLOCK_DICT => match dictionary {
+            Some(dictionary) => dictionary.decode(&frame[1..])?,
+            None => return Err(Error::DeCompress("block is coded with a dictionary, but none was given".to_string(), String::new())),
+        },
+        BLOCK_CODED if frame.len() > 1 => {
+            let mode = Mode::from_byte(frame[1])?;
+            let mut data = Vec::new();
+            for sym in coder.decode(&frame[2..])? {
+                mode.push_symbol(sym, &mut data);
+            }
+            data
+        },
+        kind => return Err(Error::DeCompress("unknown block type".to_string(), format!("{kind}"))),
+    };
+    Ok(data)
+}
+
+/// runs `work` on every item on a thread of its own, the results keep the order of `items`
+fn in_parallel<T: Send, O: Send>(items: Vec<T>, work: impl Fn(T) -> Result<O> + Sync) -> Result<Vec<O>> {
+    let work = &work;
+    std::thread::scope(|scope| {
+        let handles = items.into_iter().map(|item| scope.spawn(move || work(item))).collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(Error::Compress("a worker thread panicked".to_string(), String::new()))))
+            .collect()
+    })
+}
+
+/// replaces the frame `encoded` by a `kind` frame of `coded` if that is smaller
+fn keep_smaller(encoded: &mut Vec<u8>, kind: u8, coded: Vec<u8>) {
+    if coded.len() + 1 < encoded.len() {
+        encoded.clear();
+        encoded.push(kind);
+        encoded.extend_from_slice(&coded);
+    }
+}
+
+/// appends up to `size` bytes to `buffer`, less only at the end of the input
+fn read_block<R: Read>(input: &mut R, buffer: &mut Vec<u8>, size: usize) -> Result<usize> {
+    let want = size.saturating_sub(buffer.len()) as u64;
+    input.take(want).read_to_end(buffer).map_err(|err| Error::Compress("could not read input".to_string(), err.to_string()))
+}
+
+fn write_frame<W: Write>(output: &mut W, frame: &[u8]) -> Result<()> {
+    output.write_all(&(frame.len() as u32).to_be_bytes())
+        .and_then(|_| output.write_all(frame))
+        .map_err(|err| Error::Compress("could not write output".to_string(), err.to_string()))
+}
+
+/// reads the next frame into `frame`, returns false at the end of the stream
+fn read_frame<R: Read>(input: &mut R, frame: &mut Vec<u8>) -> Result<bool> {
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len).map_err(|err| Error::DeCompress("could not read block length".to_string(), err.to_string()))?;
+    let len = read_u32(&len)? as u64;
+    if len == 0 {
+        return Ok(false);
+    }
+    frame.clear();
+    //grows with the data actually read, a corrupted length can not force a huge allocation
+    let got = input.take(len).read_to_end(frame).map_err(|err| Error::DeCompress("could not read block".to_string(), err.to_string()))?;
+    if (got as u64) < len {
+        return Err(Error::DeCompress("block is truncated".to_string(), format!("expected {} bytes, got {}", len, got)));
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(data: &[u8], options: &Options) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        assert_eq!(compress(&mut &data[..], &mut compressed, options).unwrap(), data.len() as u64);
+        let mut decompressed = Vec::new();
+        decompress(&mut compressed.as_slice(), &mut decompressed, Decoder::Table, options.coder, options.dictionary.as_deref(), options.threads).unwrap();
+        decompressed
+    }
+
+    #[test]
+    fn multiple_blocks() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 1000).map(|i| ((i * 7) % 253) as u8 ^ (i >> 12) as u8).collect();
+        assert_eq!(round_trip(&data, &Options::default()), data);
+    }
+
+    #[test]
+    fn text_split_at_block_boundary() {
+        //'é' is 2 bytes, so the boundary falls into a char
+        let mut text = "a".repeat(BLOCK_SIZE - 1);
+        text.push_str("ééé日本");
+        let options = Options { text: true, ..Default::default() };
+        assert_eq!(round_trip(text.as_bytes(), &options), text.as_bytes());
+        let mut compressed = Vec::new();
+        assert!(compress(&mut &[b'a', 0xff][..], &mut compressed, &options).is_err());
+        //blocks smaller than a char hold one whole char
+        for (text, block_size) in [("héllo wörld", 1), ("日本語", 2), ("a😀b", 3)] {
+            let options = Options { text: true, block_size, ..Default::default() };
+            assert_eq!(round_trip(text.as_bytes(), &options), text.as_bytes());
+        }
+        let tiny = Options { text: true, block_size: 1, ..Default::default() };
+        assert!(compress(&mut &[b'a', 0xe6, 0x97][..], &mut compressed, &tiny).is_err());
+    }
+
+    /// number of blocks of each type in a compressed stream
+    fn block_types(mut compressed: &[u8]) -> (usize, usize, usize) {
+        let (mut tables, mut reused, mut stored) = (0, 0, 0);
+        let mut frame = Vec::new();
+        while read_frame(&mut compressed, &mut frame).unwrap() {
+            match frame[0] {
+                BLOCK_TABLE => tables += 1,
+                BLOCK_REUSE => reused += 1,
+                BLOCK_STORED => stored += 1,
+                _ => {},
+            }
+        }
+        (tables, reused, stored)
+    }
+
+    #[test]
+    fn changing_statistics() {
+        //a text section followed by a hex dump
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let mut data = text[..64 * 1024].to_vec();
+        for (i, b) in text[..32 * 1024].iter().enumerate() {
+            data.extend_from_slice(format!("{:02x}{}", b, if i % 16 == 15 { '\n' } else { ' ' }).as_bytes());
+        }
+        let small = Options { block_size: 16 * 1024, ..Default::default() };
+        let mut per_block = Vec::new();
+        compress(&mut data.as_slice(), &mut per_block, &small).unwrap();
+        let whole = Options { block_size: data.len(), ..Default::default() };
+        let mut single = Vec::new();
+        compress(&mut data.as_slice(), &mut single, &whole).unwrap();
+        assert!(per_block.len() < single.len());
+
+        //similar blocks share a table
+        let (tables, reused, _) = block_types(&per_block);
+        assert!(tables >= 2);
+        assert!(reused >= 1);
+        assert_eq!(round_trip(&data, &small), data);
+
+        let fresh = Options { reuse_tables: false, ..small };
+        let mut compressed = Vec::new();
+        compress(&mut data.as_slice(), &mut compressed, &fresh).unwrap();
+        assert_eq!(block_types(&compressed).1, 0);
+        assert!(per_block.len() <= compressed.len());
+    }
+
+    #[test]
+    fn stored_blocks() {
+        //random bytes followed by text, only the random block is stored
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut data: Vec<u8> = (0..64 * 1024).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect();
+        data.extend_from_slice(&std::fs::read("wizard_of_oz.txt").unwrap()[..64 * 1024]);
+        let options = Options { block_size: 64 * 1024, ..Default::default() };
+        let mut compressed = Vec::new();
+        compress(&mut data.as_slice(), &mut compressed, &options).unwrap();
+        assert_eq!(block_types(&compressed), (1, 0, 1));
+        assert!(compressed.len() < data.len());
+        assert_eq!(round_trip(&data, &options), data);
+
+        let random = &data[..64 * 1024];
+        let mut compressed = Vec::new();
+        compress(&mut &random[..], &mut compressed, &Options::default()).unwrap();
+        assert!(compressed.len() <= random.len() + 9);
+        //a single byte never pays for a table
+        assert_eq!(round_trip(b"x", &Options::default()), b"x");
+    }
+
+    #[test]
+    fn context_blocks() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let data = &text[..128 * 1024];
+        let order0 = Options { block_size: 64 * 1024, ..Default::default() };
+        let order1 = Options { context: true, ..order0.clone() };
+        let (mut plain, mut context) = (Vec::new(), Vec::new());
+        compress(&mut &data[..], &mut plain, &order0).unwrap();
+        compress(&mut &data[..], &mut context, &order1).unwrap();
+        assert!(context.len() < plain.len());
+        assert_eq!(block_types(&context), (0, 0, 0));
+        assert_eq!(round_trip(data, &order1), data);
+        let text = Options { text: true, ..order1 };
+        assert_eq!(round_trip("ça qui quoi ".repeat(1000).as_bytes(), &text), "ça qui quoi ".repeat(1000).as_bytes());
+    }
+
+    #[test]
+    fn token_blocks() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let data = &text[..128 * 1024];
+        let mut plain = Vec::new();
+        compress(&mut &data[..], &mut plain, &Options::default()).unwrap();
+        for alphabet in [Alphabet::Words, Alphabet::Grams] {
+            let options = Options { tokens: Some(alphabet), block_size: 64 * 1024, ..Default::default() };
+            let mut compressed = Vec::new();
+            compress(&mut &data[..], &mut compressed, &options).unwrap();
+            assert!(compressed.len() < plain.len());
+            assert_eq!(round_trip(data, &options), data);
+        }
+    }
+
+    #[test]
+    fn lz_blocks() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let data = &text[..128 * 1024];
+        let options = Options { lz: Some(lz77::Settings::default()), context: true, block_size: 64 * 1024, ..Default::default() };
+        let mut compressed = Vec::new();
+        compress(&mut &data[..], &mut compressed, &options).unwrap();
+        assert!(compressed.len() < data.len() / 2);
+        assert_eq!(round_trip(data, &options), data);
+    }
+
+    #[test]
+    fn bwt_blocks() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..20_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect();
+        let data = [&text[..64 * 1024], &noise[..]].concat();
+        let options = Options { bwt: true, block_size: 64 * 1024, ..Default::default() };
+        let mut compressed = Vec::new();
+        compress(&mut &data[..], &mut compressed, &options).unwrap();
+        let mut frames = &compressed[..];
+        let mut frame = Vec::new();
+        let mut kinds = Vec::new();
+        while read_frame(&mut frames, &mut frame).unwrap() {
+            kinds.push(frame[0]);
+        }
+        //the noise is not worth sorting
+        assert_eq!(kinds, vec![BLOCK_BWT, BLOCK_STORED]);
+        assert_eq!(round_trip(&data, &options), data);
+    }
+
+    #[test]
+    fn rans_blocks() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let data = &text[..96 * 1024];
+        let options = Options { coder: Coder::Rans, block_size: 32 * 1024, ..Default::default() };
+        for options in [options.clone(), Options { text: true, ..options.clone() }, Options { bwt: true, tokens: Some(Alphabet::Words), ..options.clone() }] {
+            assert_eq!(round_trip(data, &options), data);
+        }
+        //mostly one byte, where a prefix code can not go below a bit per byte
+        let skewed: Vec<u8> = (0..64 * 1024u32).map(|i| if i % 61 == 0 { b'x' } else { b' ' }).collect();
+        let mut huffman = Vec::new();
+        compress(&mut &skewed[..], &mut huffman, &Options::default()).unwrap();
+        let mut rans = Vec::new();
+        compress(&mut &skewed[..], &mut rans, &options).unwrap();
+        assert!(rans.len() * 3 < huffman.len(), "{} and {} bytes", rans.len(), huffman.len());
+        assert_eq!(round_trip(&skewed, &options), skewed);
+    }
+
+    #[test]
+    fn dictionary_blocks() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let dictionary = Arc::new(Dictionary::train(Mode::Bytes, &[text[..100_000].to_vec()], 0).unwrap());
+        let options = Options { dictionary: Some(dictionary.clone()), ..Default::default() };
+        let message = &text[150_000..150_200];
+        let mut plain = Vec::new();
+        compress(&mut &message[..], &mut plain, &Options::default()).unwrap();
+        let mut trained = Vec::new();
+        compress(&mut &message[..], &mut trained, &options).unwrap();
+        assert_eq!(trained[4], BLOCK_DICT);
+        assert!(trained.len() * 4 < plain.len() * 3, "{} and {} bytes", trained.len(), plain.len());
+        assert_eq!(round_trip(message, &options), message);
+        assert!(decompress(&mut trained.as_slice(), &mut Vec::new(), Decoder::Table, Coder::Huffman, None, 1).is_err());
+    }
+
+    #[test]
+    fn parallel_blocks() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let data = &text[..200 * 1024];
+        let serial = Options { block_size: 4 * 1024, ..Default::default() };
+        let parallel = Options { threads: 4, ..serial.clone() };
+        assert_eq!(round_trip(data, &parallel), data);
+        assert_eq!(round_trip(data, &Options { text: true, ..parallel.clone() }), data);
+
+        //only the first block of a round can reuse a table, the stream reads the same either way
+        let mut compressed = Vec::new();
+        compress(&mut &data[..], &mut compressed, &parallel).unwrap();
+        assert!(block_types(&compressed).1 >= 1);
+        let mut decompressed = Vec::new();
+        decompress(&mut compressed.as_slice(), &mut decompressed, Decoder::Table, Coder::Huffman, None, 1).unwrap();
+        assert_eq!(decompressed, data);
+
+        //reused tables are found within a round of blocks decoded at once and across rounds
+        compressed.clear();
+        compress(&mut &data[..], &mut compressed, &serial).unwrap();
+        assert!(block_types(&compressed).1 >= 2);
+        for threads in [3, 4, 16] {
+            decompressed.clear();
+            decompress(&mut compressed.as_slice(), &mut decompressed, Decoder::Tree, Coder::Huffman, None, threads).unwrap();
+            assert_eq!(decompressed, data);
+        }
+        let options = Options { bwt: true, coder: Coder::Rans, ..parallel };
+        assert_eq!(round_trip(data, &options), data);
+    }
+
+    #[test]
+    fn empty_and_truncated() {
+        assert_eq!(round_trip(&[], &Options::default()), Vec::<u8>::new());
+        let mut compressed = Vec::new();
+        compress(&mut &b"hello stream"[..], &mut compressed, &Options::default()).unwrap();
+        let mut out = Vec::new();
+        assert!(decompress(&mut &compressed[..compressed.len() - 6], &mut out, Decoder::Table, Coder::Huffman, None, 1).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::error::{Result, Error};
+use crate::bits::{BitReader, reverse_bits};
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// bits resolved by one table lookup, codes that are longer continue in a secondary table
+pub const LOOKUP_BITS: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Entry {
+    Empty,
+    Symbol { sym: u32, len: u8 },
+    Table { offset: u32, bits: u8 },
+}
+
+/// Multi-level lookup tables for decoding canonical codes from a least significant bit first
+/// stream several bits at a time. A table of `bits` bits is indexed by the next `bits` bits of
+/// the stream, its entries either hold the symbol and the number of bits its code actually
+/// uses, or point to a secondary table for the remaining bits of longer codes.
+pub struct DecodeTable {
+    entries: Vec<Entry>,
+    bits: u32,
+}
+
+impl DecodeTable {
+    /// builds the tables from (symbol, code, code length) triples
+    pub fn new(codes: &[(u32, u64, u8)]) -> Self {
+        let mut table = Self { entries: Vec::new(), bits: 0 };
+        if !codes.is_empty() {
+            table.bits = table.fill(codes).1;
+        }
+        table
+    }
+
+    /// appends a table for `codes` and returns its offset and width
+    fn fill(&mut self, codes: &[(u32, u64, u8)]) -> (usize, u32) {
+        let longest = codes.iter().map(|(_, _, len)| *len as u32).max().unwrap_or(1);
+        let bits = longest.min(LOOKUP_BITS);
+        let offset = self.entries.len();
+        self.entries.resize(offset + (1 << bits), Entry::Empty);
+
+        let mut long: BTreeMap<u64, Vec<(u32, u64, u8)>> = BTreeMap::new();
+        for &(sym, code, len) in codes {
+            let len32 = len as u32;
+            if len32 <= bits {
+                //every index that starts with the reversed code resolves to the symbol
+                let first = reverse_bits(code, len32) as usize;
+                for fill in 0..1usize << (bits - len32) {
+                    self.entries[offset + (first | (fill << len32))] = Entry::Symbol { sym, len };
+                }
+            } else {
+                let rest = len32 - bits;
+                let prefix = code >> rest;
+                long.entry(prefix).or_default().push((sym, code & ((1u64 << rest) - 1), rest as u8));
+            }
+        }
+        for (prefix, group) in long {
+            let (sub_offset, sub_bits) = self.fill(&group);
+            let idx = reverse_bits(prefix, bits) as usize;
+            self.entries[offset + idx] = Entry::Table { offset: sub_offset as u32, bits: sub_bits as u8 };
+        }
+        (offset, bits)
+    }
+
+    /// decodes the next symbol
+    pub fn decode<R: Read>(&self, reader: &mut BitReader<R>) -> Result<u32> {
+        let mut offset = 0;
+        let mut bits = self.bits;
+        loop {
+            let idx = reader.peek_bits(bits)? as usize;
+            match self.entries.get(offset + idx) {
+                Some(Entry::Symbol { sym, len }) => {
+                    reader.consume(*len as u32)?;
+                    return Ok(*sym);
+                },
+                Some(Entry::Table { offset: next, bits: next_bits }) => {
+                    reader.consume(bits)?;
+                    offset = *next as usize;
+                    bits = *next_bits as u32;
+                },
+                _ => return Err(Error::DeCompress("invalid code in bit stream".to_string(), format!("after {} bits", reader.bits_read()))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bits::{BitOrder, BitWriter};
+
+    fn codes() -> Vec<(u32, u64, u8)> {
+        //a complete canonical code with lengths 1 to 13
+        let mut codes = Vec::new();
+        let mut code = 0u64;
+        for len in 1..=12u8 {
+            codes.push((len as u32, code, len));
+            code = (code + 1) << 1;
+        }
+        codes.push((13, code, 13));
+        codes.push((14, code + 1, 13));
+        codes
+    }
+
+    #[test]
+    fn short_and_long_codes() {
+        let codes = codes();
+        let table = DecodeTable::new(&codes);
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::Lsb);
+        let order = [14u32, 1, 13, 2, 11, 12, 1, 10, 9, 14];
+        for sym in order.iter() {
+            let (_, code, len) = codes[*sym as usize - 1];
+            writer.write_code(code, len as u32).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+        let mut reader = BitReader::new(bytes.as_slice(), BitOrder::Lsb);
+        for sym in order.iter() {
+            assert_eq!(table.decode(&mut reader).unwrap(), *sym);
+        }
+    }
+
+    #[test]
+    fn truncated_and_invalid() {
+        //only "0" and "10" are valid, "11" is not
+        let table = DecodeTable::new(&[(1, 0b0, 1), (2, 0b10, 2)]);
+        let bytes = [0b0000_0011u8];
+        let mut reader = BitReader::new(&bytes[..], BitOrder::Lsb);
+        assert!(table.decode(&mut reader).is_err());
+
+        let table = DecodeTable::new(&codes());
+        let bytes = [0xffu8];
+        let mut reader = BitReader::new(&bytes[..], BitOrder::Lsb);
+        assert!(table.decode(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::error::{Result, Error};
+use crate::coder::EntropyCoder;
+use crate::bits::{BitOrder, BitReader, BitWriter};
+use std::collections::HashMap;
+
+/// How `encode` chooses the multi-byte symbols of its alphabet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Alphabet {
+    /// whole words and the runs of separators between them
+    Words,
+    /// frequent byte sequences of 2 to `MAX_GRAM` bytes
+    Grams,
+}
+
+/// symbols below are plain bytes, the dictionary entries follow
+const FIRST_TOKEN: u32 = 256;
+/// keeps every symbol a valid utf-8 scalar value in the code table
+const MAX_TOKENS: usize = 32 * 1024;
+/// longest dictionary entry, its length is stored in one byte
+const MAX_TOKEN_LEN: usize = 255;
+const MAX_GRAM: usize = 8;
+const MAX_GRAMS: usize = 1024;
+/// n-grams seen less often are not worth a dictionary entry
+const MIN_GRAM_COUNT: u32 = 4;
+
+/// Codes `data` over bytes plus a dictionary of multi-byte tokens chosen by `alphabet`.
+/// Tokens used only once are left as bytes, so every symbol is either a byte or an entry.
+///
+/// Written are 32 bits number of entries, each as 8 bits length and its bytes, then byte
+/// aligned what `coder` writes for the symbols, where the symbol 256 + i stands for entry i.
+/// `max_len` is the code length limit of `coder`, if it has one.
+pub fn encode(alphabet: Alphabet, data: &[u8], max_len: u8, coder: &dyn EntropyCoder) -> Result<Vec<u8>> {
+    //with a code length limit there is only room for that many symbols
+    let room = match max_len {
+        1..=31 => (1usize << max_len).saturating_sub(FIRST_TOKEN as usize).min(MAX_TOKENS),
+        _ => MAX_TOKENS,
+    };
+    let candidates = match alphabet {
+        Alphabet::Words => word_candidates(data),
+        Alphabet::Grams => gram_candidates(data),
+    };
+    let first = dictionary(candidates, room);
+    //keep what the tokenization actually used more than once, longer n-grams hide shorter ones
+    let mut uses: HashMap<&[u8], u32> = HashMap::new();
+    let tokens = by_symbol(&first);
+    for sym in tokenize(alphabet, data, &first) {
+        if let Some(idx) = sym.checked_sub(FIRST_TOKEN) {
+            *uses.entry(tokens[idx as usize]).or_insert(0) += 1;
+        }
+    }
+    let entries = dictionary(uses.into_iter().filter(|(_, count)| *count > 1).collect(), room);
+    let symbols = tokenize(alphabet, data, &entries);
+
+    let mut writer = BitWriter::new(Vec::new(), BitOrder::Lsb);
+    let tokens = by_symbol(&entries);
+    writer.write_bits(tokens.len() as u64, 32)?;
+    for token in tokens.iter() {
+        writer.write_byte(token.len() as u8)?;
+        for b in token.iter() {
+            writer.write_byte(*b)?;
+        }
+    }
+    let mut encoded = writer.finish()?;
+    encoded.extend_from_slice(&coder.encode(&symbols)?);
+    Ok(encoded)
+}
+
+/// Decodes a stream written by `encode` with the same kind of `coder`, every dictionary symbol gives its whole token.
+pub fn decode(bytes: &[u8], coder: &dyn EntropyCoder) -> Result<Vec<u8>> {
+    let mut reader = BitReader::new(bytes, BitOrder::Lsb);
+    let count = reader.read_bits(32)? as usize;
+    if count > MAX_TOKENS {
+        return Err(Error::DeCompress("too many dictionary entries".to_string(), format!("{count}")));
+    }
+    let mut tokens = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = reader.read_byte()? as usize;
+        let mut token = Vec::with_capacity(len);
+        for _ in 0..len {
+            token.push(reader.read_byte()?);
+        }
+        tokens.push(token);
+    }
+    let mut data = Vec::new();
+    for sym in coder.decode(&bytes[(reader.bits_read() / 8) as usize..])? {
+        match sym.checked_sub(FIRST_TOKEN) {
+            None => data.push(sym as u8),
+            Some(idx) => {
+                let token = tokens.get(idx as usize)
+                    .ok_or_else(|| Error::DeCompress("symbol is not in the dictionary".to_string(), format!("{sym}")))?;
+                data.extend_from_slice(token);
+            },
+        }
+    }
+    Ok(data)
+}
+
+fn is_word(b: u8) -> bool {
+    //bytes of multi-byte utf-8 chars count as letters, so words in any script stay whole
+    b.is_ascii_alphanumeric() || b >= 0x80
+}
+
+/// splits `data` into runs of word bytes and runs of everything else
+fn words(data: &[u8]) -> Vec<&[u8]> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    for end in 1..=data.len() {
+        if end == data.len() || is_word(data[end]) != is_word(data[start]) {
+            words.push(&data[start..end]);
+            start = end;
+        }
+    }
+    words
+}
+
+fn word_candidates(data: &[u8]) -> Vec<(&[u8], u32)> {
+    let mut counts: HashMap<&[u8], u32> = HashMap::new();
+    for word in words(data).into_iter().filter(|word| word.len() > 1 && word.len() <= MAX_TOKEN_LEN) {
+        *counts.entry(word).or_insert(0) += 1;
+    }
+    counts.into_iter().filter(|(_, count)| *count > 1).collect()
+}
+
+/// n-grams that occur at least `MIN_GRAM_COUNT` times, longer ones are only counted
+/// if their prefix is frequent as well
+fn gram_candidates(data: &[u8]) -> Vec<(&[u8], u32)> {
+    let mut candidates = Vec::new();
+    let mut last: HashMap<&[u8], u32> = HashMap::new();
+    for len in 2..=MAX_GRAM {
+        let mut counts: HashMap<&[u8], u32> = HashMap::new();
+        for gram in data.windows(len) {
+            if len == 2 || last.contains_key(&gram[..len - 1]) {
+                *counts.entry(gram).or_insert(0) += 1;
+            }
+        }
+        counts.retain(|_, count| *count >= MIN_GRAM_COUNT);
+        candidates.extend(counts.iter().map(|(gram, count)| (*gram, *count)));
+        last = counts;
+    }
+    //the counts overlap, the best ones are picked by the bytes they would save
+    by_savings(&mut candidates);
+    candidates.truncate(MAX_GRAMS);
+    candidates
+}
+
+/// orders (token, count) pairs by the bytes they save, ties broken by the token bytes
+fn by_savings(candidates: &mut [(&[u8], u32)]) {
+    candidates.sort_by(|a, b| (b.1 as usize * (b.0.len() - 1)).cmp(&(a.1 as usize * (a.0.len() - 1))).then(a.0.cmp(b.0)));
+}
+
+/// numbers the `room` tokens that save the most
+fn dictionary(mut candidates: Vec<(&[u8], u32)>, room: usize) -> HashMap<&[u8], u32> {
+    by_savings(&mut candidates);
+    candidates.iter().take(room).enumerate().map(|(idx, (token, _))| (*token, FIRST_TOKEN + idx as u32)).collect()
+}
+
+/// the tokens of `entries` in the order of their symbols
+fn by_symbol<'a>(entries: &HashMap<&'a [u8], u32>) -> Vec<&'a [u8]> {
+    let mut tokens = vec![&[][..]; entries.len()];
+    for (token, sym) in entries.iter() {
+        tokens[(*sym - FIRST_TOKEN) as usize] = *token;
+    }
+    tokens
+}
+
+/// the symbols of `data`, words or the longest n-gram at each position if they are in `entries`
+fn tokenize(alphabet: Alphabet, data: &[u8], entries: &HashMap<&[u8], u32>) -> Vec<u32> {
+    let mut symbols = Vec::with_capacity(data.len());
+    match alphabet {
+        Alphabet::Words => {
+            for word in words(data) {
+                match entries.get(word) {
+                    Some(sym) => symbols.push(*sym),
+                    None => symbols.extend(word.iter().map(|b| *b as u32)),
+                }
+            }
+        },
+        Alphabet::Grams => {
+            let mut pos = 0;
+            while pos < data.len() {
+                let longest = (2..=MAX_GRAM.min(data.len() - pos)).rev()
+                    .find_map(|len| entries.get(&data[pos..pos + len]).map(|sym| (*sym, len)));
+                match longest {
+                    Some((sym, len)) => {
+                        symbols.push(sym);
+                        pos += len;
+                    },
+                    None => {
+                        symbols.push(data[pos] as u32);
+                        pos += 1;
+                    },
+                }
+            }
+        },
+    }
+    symbols
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::coder::Coder;
+    use crate::huffman::{Decoder, Huffman};
+
+    fn round_trip(alphabet: Alphabet, data: &[u8]) -> (Vec<u8>, usize) {
+        let coder = Coder::Huffman.build(0, Decoder::Table);
+        let encoded = encode(alphabet, data, 0, coder.as_ref()).unwrap();
+        (decode(&encoded, coder.as_ref()).unwrap(), encoded.len())
+    }
+
+    #[test]
+    fn words_and_grams() {
+        let text = std::fs::read("wizard_of_oz.txt").unwrap();
+        let data = &text[..128 * 1024];
+        let mut order0 = Huffman::from_raw(data);
+        order0.create_tree().unwrap();
+        order0.codes();
+        order0.encode().unwrap();
+        let mut plain = Vec::new();
+        order0.read_bytes_into(&mut plain);
+        for alphabet in [Alphabet::Words, Alphabet::Grams] {
+            let (decoded, size) = round_trip(alphabet, data);
+            assert_eq!(decoded, data);
+            assert!(size < plain.len() * 85 / 100, "{:?} gave {} bytes", alphabet, size);
+        }
+    }
+
+    #[test]
+    fn splits_words() {
+        assert_eq!(words(b"Hi, you... K\xc3\xb6ln"), vec![&b"Hi"[..], b", ", b"you", b"... ", b"K\xc3\xb6ln"]);
+        assert!(words(b"").is_empty());
+        for data in [&b""[..], b"x", b"the cat and the hat and the bat", &[0xff, 0, 0xff, 0, 0xff, 0, 0xff, 0, 0xff, 0]] {
+            assert_eq!(round_trip(Alphabet::Words, data).0, data);
+            assert_eq!(round_trip(Alphabet::Grams, data).0, data);
+        }
+        //a code length limit shrinks the dictionary
+        let data = "alpha beta gamma delta ".repeat(50);
+        let coder = Coder::Huffman.build(9, Decoder::Tree);
+        let encoded = encode(Alphabet::Words, data.as_bytes(), 9, coder.as_ref()).unwrap();
+        assert_eq!(decode(&encoded, coder.as_ref()).unwrap(), data.as_bytes());
+    }
+}
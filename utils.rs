@@ -1,6 +1,9 @@
 use std::convert::TryInto;
 use super::error::{Result, Error};
-use std::path::PathBuf;
+use crate::format::Meta;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 
 fn to_array_4(data: &[u8]) -> Result<&[u8; 4]> {
     data.try_into().map_err(|err| Error::Conversion("to_array_4".to_string(), format!("{err}")))
@@ -8,19 +11,45 @@ fn to_array_4(data: &[u8]) -> Result<&[u8; 4]> {
 
 pub fn read_u32(input: &[u8]) -> Result<u32> {
     if input.len() != 4 {
-        Error::Conversion("expected exactly 4 bytes".to_string(), "no".to_string());
+        return Err(Error::Conversion("expected exactly 4 bytes".to_string(), format!("got {}", input.len())));
     }
     let bytes = *to_array_4(input)?;
     Ok(u32::from_be_bytes(bytes))
 }
 
-pub fn inc_bit(bit: &mut u8) -> bool {
-    if *bit == 7 {
-        *bit = 0;
-        return true;
-    } 
-    *bit += 1;
-    false
+/// reads one utf-8 encoded char from the start of `input`,
+/// returns it with the number of bytes it occupied
+pub fn read_char(input: &[u8]) -> Result<(char, usize)> {
+    let width = match input.first() {
+        Some(b) => utf8_width(*b)?,
+        None => return Err(Error::Conversion("expected a utf-8 char".to_string(), "no bytes left".to_string())),
+    };
+    if input.len() < width {
+        return Err(Error::Conversion("utf-8 char is truncated".to_string(), format!("expected {width} bytes")));
+    }
+    let s = std::str::from_utf8(&input[..width]).map_err(|err| Error::Conversion("invalid utf-8 char".to_string(), err.to_string()))?;
+    match s.chars().next() {
+        Some(ch) => Ok((ch, width)),
+        None => Err(Error::Conversion("expected a utf-8 char".to_string(), String::new())),
+    }
+}
+
+/// number of bytes of the utf-8 char that starts with `lead`
+pub fn utf8_width(lead: u8) -> Result<usize> {
+    match lead {
+        b if b < 0x80 => Ok(1),
+        b if b & 0xe0 == 0xc0 => Ok(2),
+        b if b & 0xf0 == 0xe0 => Ok(3),
+        b if b & 0xf8 == 0xf0 => Ok(4),
+        b => Err(Error::Conversion("invalid utf-8 leading byte".to_string(), format!("{b:#x}"))),
+    }
+}
+
+/// appends the utf-8 encoding of the scalar value `sym`
+pub fn write_char(sym: u32, buffer: &mut Vec<u8>) {
+    let ch = char::from_u32(sym).unwrap_or(char::REPLACEMENT_CHARACTER);
+    let mut buf = [0u8; 4];
+    buffer.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
 }
 
 pub fn in_file_to_out_file(mut in_file: PathBuf) -> PathBuf {
@@ -31,15 +60,74 @@ pub fn in_file_to_out_file(mut in_file: PathBuf) -> PathBuf {
     }
     in_file
 }
-pub fn out_file_to_in_file(mut out_file: PathBuf) -> PathBuf {
-    out_file.set_extension("txt"); //TODO at compression add in_file extension
+/// `in_file` with `.ext` appended, as gzip names its output
+pub fn append_extension(in_file: PathBuf, ext: &str) -> PathBuf {
+    let mut name = in_file.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{ext}"));
+    let mut out_file = in_file.with_file_name(&name);
     if out_file.exists() {
-        let file = out_file.file_stem().unwrap();
-        out_file.set_file_name(format!("{}_copy.txt", file.to_str().unwrap()).as_str());
+        let file = in_file.file_name().unwrap_or_default().to_string_lossy().to_string();
+        out_file.set_file_name(format!("{}_copy.{}", file, ext).as_str());
     }
     out_file
 }
 
+/// the file to decompress `out_file` into, named `name` (the name stored at compression)
+/// next to it, or `out_file` without the '.huf' (or '.gz', '.zz', '.deflate') extension if no name was stored
+pub fn out_file_to_in_file(mut out_file: PathBuf, name: Option<&str>) -> PathBuf {
+    match name {
+        Some(name) => out_file.set_file_name(name),
+        None if out_file.extension().is_some_and(|ext| ["huf", "gz", "zz", "deflate"].iter().any(|known| ext == *known)) => { out_file.set_extension(""); },
+        None => { out_file.set_extension("out"); },
+    }
+    if out_file.exists() {
+        let file = out_file.file_stem().unwrap().to_str().unwrap().to_string();
+        match out_file.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_string()) {
+            Some(ext) => out_file.set_file_name(format!("{}_copy.{}", file, ext).as_str()),
+            None => out_file.set_file_name(format!("{}_copy", file).as_str()),
+        }
+    }
+    out_file
+}
+
+/// permissions and modification time of the file at `path`
+pub fn file_meta(path: &Path) -> Option<Meta> {
+    let meta = fs::metadata(path).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(Meta { permissions: permissions(&meta), mtime })
+}
+
+#[cfg(unix)]
+fn permissions(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permissions(meta: &fs::Metadata) -> u32 {
+    if meta.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+/// applies stored permissions and modification time to the file at `path`
+pub fn restore_meta(path: &Path, meta: Meta) -> Result<()> {
+    let file = fs::File::open(path).map_err(|err| Error::Finalizing("could not open out file to restore its attributes".to_string(), err.to_string()))?;
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(meta.mtime)).map_err(|err| Error::Finalizing("could not restore modification time".to_string(), err.to_string()))?;
+    let mut perms = file.metadata().map_err(|err| Error::Finalizing("could not read out file attributes".to_string(), err.to_string()))?.permissions();
+    set_permissions(&mut perms, meta.permissions);
+    fs::set_permissions(path, perms).map_err(|err| Error::Finalizing("could not restore permissions".to_string(), err.to_string()))
+}
+
+#[cfg(unix)]
+fn set_permissions(perms: &mut fs::Permissions, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    perms.set_mode(mode & 0o7777);
+}
+
+#[cfg(not(unix))]
+fn set_permissions(perms: &mut fs::Permissions, mode: u32) {
+    perms.set_readonly(mode & 0o200 == 0);
+}
+
 
 #[cfg(test)]
 mod test {
@@ -54,13 +142,22 @@ mod test {
     }
 
     #[test]
-    fn do_inc_bit() {
-        let mut bit = 6;
-        assert!(!inc_bit(&mut bit));
-        assert_eq!(bit, 7);
-        assert!(inc_bit(&mut bit));
-        assert_eq!(bit, 0);
+    fn do_read_char() {
+        assert_eq!(read_char(b"a").unwrap(), ('a', 1));
+        assert_eq!(read_char("é!".as_bytes()).unwrap(), ('é', 2));
+        assert_eq!(read_char("日".as_bytes()).unwrap(), ('日', 3));
+        assert!(read_char(&[0xe6, 0x97]).is_err());
+        assert!(read_char(&[0xff]).is_err());
+        assert!(read_char(&[]).is_err());
+    }
 
+    #[test]
+    fn do_write_char() {
+        let mut buf = Vec::new();
+        write_char('日' as u32, &mut buf);
+        write_char(0xff, &mut buf);
+        assert_eq!(read_char(&buf).unwrap(), ('日', 3));
+        assert_eq!(read_char(&buf[3..]).unwrap(), ('\u{ff}', 2));
     }
 
     #[test]
@@ -68,18 +165,42 @@ mod test {
         let in_file = PathBuf::from("test.txt");
         let out_file = in_file_to_out_file(in_file);
         assert_eq!(out_file.as_path(), Path::new("test.huf"));
+        let out_file = append_extension(PathBuf::from("dir/test.txt"), "gz");
+        assert_eq!(out_file.as_path(), Path::new("dir/test.txt.gz"));
     }
     #[test]
     fn out_to_in_file() {
         let out_file = PathBuf::from("test.huf");
-        let in_file = out_file_to_in_file(out_file);
-        assert_eq!(in_file.as_path(), Path::new("test.txt"));
+        let in_file = out_file_to_in_file(out_file.clone(), Some("test.csv"));
+        assert_eq!(in_file.as_path(), Path::new("test.csv"));
+        let in_file = out_file_to_in_file(PathBuf::from("dir/test.huf"), Some("report.csv"));
+        assert_eq!(in_file.as_path(), Path::new("dir/report.csv"));
+        let in_file = out_file_to_in_file(out_file, None);
+        assert_eq!(in_file.as_path(), Path::new("test"));
+        let in_file = out_file_to_in_file(PathBuf::from("dir/test.txt.gz"), None);
+        assert_eq!(in_file.as_path(), Path::new("dir/test.txt"));
+        let in_file = out_file_to_in_file(PathBuf::from("test.bin"), None);
+        assert_eq!(in_file.as_path(), Path::new("test.out"));
     }
 
     #[test]
     fn file_that_exists() {
         let in_file = PathBuf::from("blank.huf");
-        let out_file = out_file_to_in_file(in_file);
+        let out_file = out_file_to_in_file(in_file, Some("blank.txt"));
         assert_eq!(out_file.as_path(), Path::new("blank_copy.txt"));
     }
+
+    #[test]
+    fn meta_round_trip() {
+        let path = std::env::temp_dir().join(format!("huf_meta_{}", std::process::id()));
+        fs::write(&path, b"meta").unwrap();
+        let meta = Meta { permissions: 0o640, mtime: 1_000_000_000 };
+        restore_meta(&path, meta).unwrap();
+        let restored = file_meta(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.mtime, meta.mtime);
+        if cfg!(unix) {
+            assert_eq!(restored.permissions, meta.permissions);
+        }
+    }
 }